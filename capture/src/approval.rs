@@ -0,0 +1,119 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use common::secure::PeerIdentity;
+use log::{error, info};
+
+use crate::ui::UI;
+
+// What the display is allowed to do once connected
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    ViewOnly,
+    Input,
+}
+
+// The answer to the approval prompt
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Approval {
+    ViewOnly,
+    AllowInput,
+    // Allow input, and from now on without asking
+    Trust,
+    Reject,
+}
+
+// Displays which are given input without asking, stored one per line as
+// "<fingerprint> <name>"
+pub struct TrustList {
+    path: PathBuf,
+    entries: Vec<(String, String)>,
+}
+
+impl TrustList {
+    pub fn load(path: &Path) -> Self {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(s) => s
+                .lines()
+                .filter_map(|l| {
+                    let (fingerprint, name) = l.trim().split_once(' ').unwrap_or((l.trim(), ""));
+                    (!fingerprint.is_empty()).then(|| (fingerprint.into(), name.into()))
+                })
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => {
+                error!("could not read trusted displays from {:?}: {}", path, e);
+                vec![]
+            }
+        };
+
+        Self {
+            path: path.into(),
+            entries,
+        }
+    }
+
+    pub fn contains(&self, peer: &PeerIdentity) -> bool {
+        self.entries.iter().any(|(f, _)| *f == peer.fingerprint)
+    }
+
+    pub fn add(&mut self, peer: &PeerIdentity) {
+        self.entries
+            .push((peer.fingerprint.clone(), peer.name.clone()));
+
+        let s: String = self
+            .entries
+            .iter()
+            .map(|(f, n)| format!("{} {}\n", f, n))
+            .collect();
+        if let Err(e) = std::fs::write(&self.path, s) {
+            error!("could not save trusted displays to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Decides what the display may do, asking in the ui unless it's already trusted.
+// Returns None if it was rejected or the ui was closed
+pub fn approve(
+    peer: &PeerIdentity,
+    trust: &mut TrustList,
+    ui: &Arc<Mutex<UI>>,
+    ui_thread: &JoinHandle<()>,
+) -> Option<Access> {
+    if trust.contains(peer) {
+        info!(
+            "trusted display {:?} ({}) connected",
+            peer.name, peer.fingerprint
+        );
+        return Some(Access::Input);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    ui.lock().unwrap().ask_approval(peer.clone(), tx);
+
+    let approval = loop {
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(approval) => break approval,
+            Err(_) if ui_thread.is_finished() => return None,
+            Err(_) => continue,
+        }
+    };
+    info!(
+        "display {:?} ({}): {:?}",
+        peer.name, peer.fingerprint, approval
+    );
+
+    match approval {
+        Approval::ViewOnly => Some(Access::ViewOnly),
+        Approval::AllowInput => Some(Access::Input),
+        Approval::Trust => {
+            trust.add(peer);
+            Some(Access::Input)
+        }
+        Approval::Reject => None,
+    }
+}
@@ -1,4 +1,7 @@
-use crate::audio_capture::AudioCapturer;
+use crate::{audio_capture::AudioCapturer, config::Config};
+
+// Loss the in-band fec is tuned for, higher spends more of the bitrate on it
+const EXPECTED_LOSS_PERC: u8 = 10;
 
 pub struct AudioEncoder {
     pub source: AudioCapturer,
@@ -6,15 +9,22 @@ pub struct AudioEncoder {
 }
 
 impl AudioEncoder {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
+        let mut encoder = audiopus::coder::Encoder::new(
+            audiopus::SampleRate::Hz48000,
+            audiopus::Channels::Stereo,
+            audiopus::Application::Audio,
+        )
+        .unwrap();
+
+        // Include a low bitrate copy of each packet in the next, so the display can
+        // recover a single lost packet
+        encoder.set_inband_fec(true).unwrap();
+        encoder.set_packet_loss_perc(EXPECTED_LOSS_PERC).unwrap();
+
         Self {
-            source: AudioCapturer::new(),
-            encoder: audiopus::coder::Encoder::new(
-                audiopus::SampleRate::Hz48000,
-                audiopus::Channels::Stereo,
-                audiopus::Application::Audio,
-            )
-            .unwrap(),
+            source: AudioCapturer::new(config),
+            encoder,
         }
     }
 
@@ -2,6 +2,8 @@ use std::{cell::RefCell, ops::Deref, rc::Rc};
 
 use pulse::{def::BufferAttr, mainloop::standard::IterateResult, stream::PeekResult};
 
+use crate::config::Config;
+
 // Capture audio with pulseaudio
 // pactl load-module module-null-sink sink_name=sink1
 // Use qpwgraph to connect applications
@@ -13,7 +15,7 @@ pub struct AudioCapturer {
 }
 
 impl AudioCapturer {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         // Create a spec for our input
         let spec = pulse::sample::Spec {
             format: pulse::sample::Format::F32le,
@@ -44,7 +46,7 @@ impl AudioCapturer {
         stream
             .borrow_mut()
             .connect_record(
-                Some("sink1.monitor"),
+                Some(config.audio_source.as_str()),
                 Some(&BufferAttr {
                     maxlength: 7680 * 8,
                     tlength: u32::MAX,
@@ -97,6 +99,11 @@ impl AudioCapturer {
     pub fn uncork(&self) {
         self.stream.borrow_mut().uncork(None);
     }
+
+    // Stops recording, so nothing stale is sent once uncorked again
+    pub fn cork(&self) {
+        self.stream.borrow_mut().cork(None);
+    }
 }
 
 fn wait_until_ctx_ready(
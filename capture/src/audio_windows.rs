@@ -1,7 +1,9 @@
+use crate::config::Config;
+
 pub struct AudioCapturer {}
 
 impl AudioCapturer {
-    pub fn new() -> Self {
+    pub fn new(_config: &Config) -> Self {
         Self {}
     }
 
@@ -10,4 +12,6 @@ impl AudioCapturer {
     }
 
     pub fn uncork(&self) {}
+
+    pub fn cork(&self) {}
 }
@@ -7,14 +7,16 @@ use std::{
 use ashpd::desktop::screencast::{SourceType, Stream};
 use pipewire::{self as pw, properties::properties, stream::StreamRef};
 
-use crate::{ui::FrameLatencyInfo, CAPTURE_HEIGHT, CAPTURE_WIDTH, FRAME_RATE};
+use crate::{config::Config, ui::FrameLatencyInfo};
 
 pub struct VideoCapturer {
     cur_image: Arc<Mutex<Vec<u8>>>,
 }
 
 impl VideoCapturer {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
+        let (width, height) = (config.region.width, config.region.height);
+        let frame_rate = config.frame_rate;
         let tokio_rt = tokio::runtime::Runtime::new().unwrap();
         let (sel_stream, fd) = tokio_rt.block_on(Self::get_stream());
 
@@ -80,10 +82,7 @@ impl VideoCapturer {
                 pw::spa::pod::property!(
                     pw::spa::param::format::FormatProperties::VideoSize,
                     Rectangle,
-                    pw::spa::utils::Rectangle {
-                        width: CAPTURE_WIDTH,
-                        height: CAPTURE_HEIGHT
-                    }
+                    pw::spa::utils::Rectangle { width, height }
                 ),
                 pw::spa::pod::property!(
                     pw::spa::param::format::FormatProperties::VideoFramerate,
@@ -91,7 +90,7 @@ impl VideoCapturer {
                     Range,
                     Fraction,
                     pw::spa::utils::Fraction {
-                        num: FRAME_RATE,
+                        num: frame_rate,
                         denom: 1
                     },
                     pw::spa::utils::Fraction { num: 0, denom: 1 },
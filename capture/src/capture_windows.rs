@@ -9,9 +9,7 @@ use windows_capture::{
     settings::Settings,
 };
 
-use crate::{
-    ui::FrameLatencyInfo, CAPTURE_HEIGHT, CAPTURE_OFFSET_X, CAPTURE_OFFSET_Y, CAPTURE_WIDTH,
-};
+use crate::{config::Config, ui::FrameLatencyInfo};
 
 pub struct VideoCapturer {
     cur_image: Arc<Mutex<Vec<u8>>>,
@@ -19,11 +17,16 @@ pub struct VideoCapturer {
 }
 
 impl VideoCapturer {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         let cur_image = Arc::new(Mutex::new(vec![]));
 
+        let monitor = match config.monitor {
+            Some(index) => Monitor::from_index(index).unwrap(),
+            None => Monitor::primary().unwrap(),
+        };
+
         let control = CaptureInternal::start_free_threaded(Settings::new(
-            Monitor::primary().unwrap(),
+            monitor,
             windows_capture::settings::CursorCaptureSettings::WithCursor,
             windows_capture::settings::DrawBorderSettings::Default,
             windows_capture::settings::ColorFormat::Bgra8,
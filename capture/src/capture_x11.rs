@@ -15,19 +15,23 @@ use x11rb::{
 };
 
 use crate::{
-    ui::FrameLatencyInfo, CAPTURE_HEIGHT, CAPTURE_OFFSET_X, CAPTURE_OFFSET_Y, CAPTURE_WIDTH,
+    config::{Config, Region},
+    ui::FrameLatencyInfo,
 };
 
 pub struct VideoCapturer {
     xconn: RustConnection,
     screen: Screen,
+    region: Region,
 
     shm_buf: File,
     shm_seg: u32,
 }
 
 impl VideoCapturer {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
+        let region = config.region;
+
         // Connect to X11
         let (xconn, screen_num) = x11rb::connect(None).unwrap();
         let screen = xconn.setup().roots[screen_num].clone();
@@ -38,7 +42,7 @@ impl VideoCapturer {
         // Create shared memory segment for capturing frames
         let shm_seg = xconn.generate_id().unwrap();
         let shm_reply = xconn
-            .shm_create_segment(shm_seg, CAPTURE_WIDTH * CAPTURE_HEIGHT * 4, false)
+            .shm_create_segment(shm_seg, region.width * region.height * 4, false)
             .unwrap()
             .reply()
             .unwrap();
@@ -48,6 +52,7 @@ impl VideoCapturer {
         Self {
             xconn,
             screen,
+            region,
             shm_buf,
             shm_seg,
         }
@@ -55,14 +60,16 @@ impl VideoCapturer {
 
     pub fn capture_frame(&mut self) -> (Vec<u8>, FrameLatencyInfo) {
         let mut f = FrameLatencyInfo::new();
+        let region = self.region;
+
         // Capture screen from x11, using shared memory
         self.xconn
             .shm_get_image(
                 self.screen.root,
-                CAPTURE_OFFSET_X as i16,
-                CAPTURE_OFFSET_Y as i16,
-                CAPTURE_WIDTH as u16,
-                CAPTURE_HEIGHT as u16,
+                region.offset_x as i16,
+                region.offset_y as i16,
+                region.width as u16,
+                region.height as u16,
                 0x00ffffff,
                 ImageFormat::Z_PIXMAP.into(),
                 self.shm_seg,
@@ -88,11 +95,11 @@ impl VideoCapturer {
             .unwrap();
         f.measure("get_cursor_image");
 
-        let ox = cursor.x as i64 - CAPTURE_OFFSET_X as i64;
-        let oy = cursor.y as i64 - CAPTURE_OFFSET_Y as i64;
+        let ox = cursor.x as i64 - region.offset_x as i64;
+        let oy = cursor.y as i64 - region.offset_y as i64;
 
         // Copy cursor onto image if it is within bounds
-        if ox >= 0 && ox <= CAPTURE_WIDTH as i64 && oy >= 0 && oy <= CAPTURE_HEIGHT as i64 {
+        if ox >= 0 && ox <= region.width as i64 && oy >= 0 && oy <= region.height as i64 {
             for x in (ox as i64 - cursor.xhot as i64)
                 ..(ox as i64 + cursor.width as i64 - cursor.xhot as i64)
             {
@@ -104,7 +111,7 @@ impl VideoCapturer {
                     let idx = (cx + cy * cursor.width as i64) as usize;
                     let cb = cursor.cursor_image[idx];
 
-                    let img_offset = (y * CAPTURE_WIDTH as i64 + x) * 4;
+                    let img_offset = (y * region.width as i64 + x) * 4;
                     if img_offset < 0 || img_offset >= image.len() as i64 {
                         continue;
                     }
@@ -0,0 +1,235 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::Parser;
+use serde::Deserialize;
+
+#[derive(Parser)]
+#[command(about = "Shares this computer's screen and audio with a display client")]
+struct Args {
+    /// TOML config file, command line options override it
+    #[arg(long, default_value = "capture.toml")]
+    config: PathBuf,
+
+    /// Address of the repeater
+    #[arg(long)]
+    server: Option<String>,
+    /// Session code to use instead of a random one
+    #[arg(long)]
+    session: Option<String>,
+    /// File listing the displays allowed to connect without asking
+    #[arg(long)]
+    trust_file: Option<PathBuf>,
+    /// Directory to save files sent by the display in
+    #[arg(long)]
+    received_dir: Option<PathBuf>,
+
+    /// Width of the captured region
+    #[arg(long)]
+    width: Option<u32>,
+    /// Height of the captured region
+    #[arg(long)]
+    height: Option<u32>,
+    /// Horizontal offset of the captured region
+    #[arg(long)]
+    offset_x: Option<u32>,
+    /// Vertical offset of the captured region
+    #[arg(long)]
+    offset_y: Option<u32>,
+    /// Monitor to capture (windows only, starting at 1)
+    #[arg(long)]
+    monitor: Option<usize>,
+
+    #[arg(long)]
+    frame_rate: Option<u32>,
+    /// Average video bitrate in kbit/s
+    #[arg(long)]
+    bitrate_kbps: Option<u32>,
+    /// Maximum video bitrate in kbit/s
+    #[arg(long)]
+    max_bitrate_kbps: Option<u32>,
+    /// Lowest average bitrate in kbit/s that congestion control will drop to
+    #[arg(long)]
+    min_bitrate_kbps: Option<u32>,
+    /// Adapt the bitrate to the display's loss and delay reports
+    #[arg(long)]
+    adaptive_bitrate: Option<bool>,
+    /// Send a parity packet after this many packets so single losses are repaired
+    /// without a retransmission, 0 disables it
+    #[arg(long)]
+    fec_group_size: Option<usize>,
+
+    /// Pulseaudio source to capture audio from
+    #[arg(long)]
+    audio_source: Option<String>,
+
+    /// Share the clipboard with the display. Can be toggled in the ui
+    #[arg(long)]
+    clipboard: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Region {
+    pub width: u32,
+    pub height: u32,
+    pub offset_x: u32,
+    pub offset_y: u32,
+}
+
+impl Default for Region {
+    fn default() -> Self {
+        Self {
+            width: 2560,
+            height: 1440,
+            offset_x: 3840,
+            offset_y: 240,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub server: String,
+    pub session: Option<String>,
+    pub trust_file: PathBuf,
+    pub received_dir: PathBuf,
+
+    pub region: Region,
+    pub monitor: Option<usize>,
+
+    pub frame_rate: u32,
+    pub bitrate_kbps: u32,
+    pub max_bitrate_kbps: u32,
+    pub min_bitrate_kbps: u32,
+    pub adaptive_bitrate: bool,
+    pub fec_group_size: usize,
+
+    pub audio_source: String,
+
+    pub clipboard: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: "dw.superkooks.com:42069".into(),
+            session: None,
+            trust_file: "trusted_displays.txt".into(),
+            received_dir: "received_files".into(),
+            region: Region::default(),
+            monitor: None,
+            frame_rate: 10,
+            bitrate_kbps: 8 << 10,
+            max_bitrate_kbps: 12 << 10,
+            min_bitrate_kbps: 1 << 10,
+            adaptive_bitrate: true,
+            fec_group_size: 0,
+            audio_source: "sink1.monitor".into(),
+            clipboard: true,
+        }
+    }
+}
+
+impl Config {
+    // Reads the config file and applies the command line options on top.
+    // Exits with a message if either is invalid
+    pub fn load() -> Self {
+        let args = Args::parse();
+
+        let mut config: Config = match std::fs::read_to_string(&args.config) {
+            Ok(s) => toml::from_str(&s).unwrap_or_else(|e| {
+                eprintln!("invalid config file {:?}: {}", args.config, e);
+                std::process::exit(1);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => {
+                eprintln!("could not read config file {:?}: {}", args.config, e);
+                std::process::exit(1);
+            }
+        };
+
+        if let Some(server) = args.server {
+            config.server = server;
+        }
+        if args.session.is_some() {
+            config.session = args.session;
+        }
+        if let Some(trust_file) = args.trust_file {
+            config.trust_file = trust_file;
+        }
+        if let Some(received_dir) = args.received_dir {
+            config.received_dir = received_dir;
+        }
+        if let Some(width) = args.width {
+            config.region.width = width;
+        }
+        if let Some(height) = args.height {
+            config.region.height = height;
+        }
+        if let Some(offset_x) = args.offset_x {
+            config.region.offset_x = offset_x;
+        }
+        if let Some(offset_y) = args.offset_y {
+            config.region.offset_y = offset_y;
+        }
+        if args.monitor.is_some() {
+            config.monitor = args.monitor;
+        }
+        if let Some(frame_rate) = args.frame_rate {
+            config.frame_rate = frame_rate;
+        }
+        if let Some(bitrate_kbps) = args.bitrate_kbps {
+            config.bitrate_kbps = bitrate_kbps;
+        }
+        if let Some(max_bitrate_kbps) = args.max_bitrate_kbps {
+            config.max_bitrate_kbps = max_bitrate_kbps;
+        }
+        if let Some(min_bitrate_kbps) = args.min_bitrate_kbps {
+            config.min_bitrate_kbps = min_bitrate_kbps;
+        }
+        if let Some(adaptive_bitrate) = args.adaptive_bitrate {
+            config.adaptive_bitrate = adaptive_bitrate;
+        }
+        if let Some(fec_group_size) = args.fec_group_size {
+            config.fec_group_size = fec_group_size;
+        }
+        if let Some(audio_source) = args.audio_source {
+            config.audio_source = audio_source;
+        }
+        if let Some(clipboard) = args.clipboard {
+            config.clipboard = clipboard;
+        }
+
+        if config.frame_rate == 0 {
+            eprintln!("frame rate must be at least 1");
+            std::process::exit(1);
+        }
+
+        config
+    }
+
+    // Where the top left of the captured image is on the desktop, if known
+    pub fn capture_origin(&self) -> Option<(i32, i32)> {
+        if cfg!(all(target_os = "linux", not(feature = "wayland"))) {
+            return Some((self.region.offset_x as i32, self.region.offset_y as i32));
+        }
+
+        // The whole monitor is captured, but where a monitor other than the primary
+        // one sits on the desktop isn't known
+        match self.monitor {
+            Some(_) => None,
+            None => Some((0, 0)),
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs(1) / self.frame_rate
+    }
+
+    // Scales the maximum bitrate with the average, keeping the configured ratio between them
+    pub fn max_bitrate_for(&self, bitrate_kbps: u32) -> u32 {
+        (bitrate_kbps as u64 * self.max_bitrate_kbps as u64 / self.bitrate_kbps.max(1) as u64)
+            as u32
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+use common::msgs::ReceiverReport;
+
+use crate::config::Config;
+
+// Loss or queueing above these means the network is congested
+const CONGESTED_LOSS: f64 = 0.1;
+const CONGESTED_DELAY_TREND_US: f64 = 10_000.;
+
+// Loss and queueing below these means there is room to grow
+const CLEAR_LOSS: f64 = 0.02;
+const CLEAR_DELAY_TREND_US: f64 = 2_000.;
+
+const DECREASE_FACTOR: f64 = 0.85;
+const DECREASE_INTERVAL: Duration = Duration::from_millis(500);
+// Wait after a decrease before probing for more bandwidth
+const INCREASE_HOLDOFF: Duration = Duration::from_millis(1000);
+
+// Picks the video bitrate from the display's receiver reports.
+// Backs off multiplicatively on loss or growing delay, and increases additively otherwise
+pub struct CongestionController {
+    bitrate_kbps: u32,
+    min_kbps: u32,
+    max_kbps: u32,
+    last_decrease: Instant,
+}
+
+impl CongestionController {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            bitrate_kbps: config.bitrate_kbps,
+            min_kbps: config.min_bitrate_kbps.min(config.bitrate_kbps),
+            max_kbps: config.bitrate_kbps,
+            last_decrease: Instant::now(),
+        }
+    }
+
+    // Returns the new bitrate if it should change
+    pub fn on_report(&mut self, report: &ReceiverReport) -> Option<u32> {
+        let total = report.received + report.lost;
+        let loss = if total > 0 {
+            report.lost as f64 / total as f64
+        } else {
+            0.
+        };
+
+        let since_decrease = Instant::now().duration_since(self.last_decrease);
+        let new = if loss > CONGESTED_LOSS || report.delay_trend_us > CONGESTED_DELAY_TREND_US {
+            if since_decrease < DECREASE_INTERVAL {
+                return None;
+            }
+
+            self.last_decrease = Instant::now();
+            ((self.bitrate_kbps as f64 * DECREASE_FACTOR) as u32).max(self.min_kbps)
+        } else if loss < CLEAR_LOSS
+            && report.delay_trend_us < CLEAR_DELAY_TREND_US
+            && since_decrease > INCREASE_HOLDOFF
+        {
+            (self.bitrate_kbps + self.max_kbps / 20).min(self.max_kbps)
+        } else {
+            return None;
+        };
+
+        if new == self.bitrate_kbps {
+            return None;
+        }
+
+        self.bitrate_kbps = new;
+        Some(new)
+    }
+}
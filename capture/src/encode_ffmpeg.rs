@@ -1,19 +1,22 @@
 use std::ffi::CString;
 
+use common::clock;
 use ffmpeg_sys_next as ffmpeg;
 
-use crate::{
-    ui::FrameLatencyInfo, video_capture::VideoCapturer, CAPTURE_HEIGHT, CAPTURE_WIDTH, FRAME_RATE,
-};
+use crate::{config::Config, ui::FrameLatencyInfo, video_capture::VideoCapturer};
 
 pub struct VideoEncoder {
     capturer: VideoCapturer,
     encoder: *mut ffmpeg::AVCodecContext,
     pts: i64,
+    force_keyframe: bool,
+
+    width: u32,
+    height: u32,
 }
 
 impl VideoEncoder {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         let codec = unsafe { ffmpeg::avcodec_find_encoder(ffmpeg::AVCodecID::AV_CODEC_ID_H264) };
 
         let encoder = unsafe { ffmpeg::avcodec_alloc_context3(codec) };
@@ -22,14 +25,14 @@ impl VideoEncoder {
             (*encoder).sample_aspect_ratio.num = 16;
             (*encoder).sample_aspect_ratio.den = 9;
             (*encoder).time_base.num = 1;
-            (*encoder).time_base.den = FRAME_RATE as i32;
-            (*encoder).framerate.num = FRAME_RATE as i32;
+            (*encoder).time_base.den = config.frame_rate as i32;
+            (*encoder).framerate.num = config.frame_rate as i32;
             (*encoder).framerate.den = 1;
-            (*encoder).bit_rate = 8 << 20;
-            (*encoder).rc_max_rate = 12 << 20;
-            (*encoder).rc_buffer_size = (12 << 20) / 60;
-            (*encoder).width = CAPTURE_WIDTH as i32;
-            (*encoder).height = CAPTURE_HEIGHT as i32;
+            (*encoder).bit_rate = (config.bitrate_kbps as i64) << 10;
+            (*encoder).rc_max_rate = (config.max_bitrate_kbps as i64) << 10;
+            (*encoder).rc_buffer_size = ((config.max_bitrate_kbps << 10) / 60) as i32;
+            (*encoder).width = config.region.width as i32;
+            (*encoder).height = config.region.height as i32;
             (*encoder).pix_fmt = ffmpeg::AVPixelFormat::AV_PIX_FMT_YUV420P;
 
             let name = CString::new("preset").unwrap();
@@ -39,6 +42,11 @@ impl VideoEncoder {
             let name = CString::new("tune").unwrap();
             let val = CString::new("zerolatency").unwrap();
             ffmpeg::av_opt_set((*encoder).priv_data, name.as_ptr(), val.as_ptr(), 0);
+
+            // Make frames forced to be I frames into IDR frames
+            let name = CString::new("forced-idr").unwrap();
+            let val = CString::new("1").unwrap();
+            ffmpeg::av_opt_set((*encoder).priv_data, name.as_ptr(), val.as_ptr(), 0);
         }
 
         unsafe {
@@ -46,24 +54,43 @@ impl VideoEncoder {
         }
 
         Self {
-            capturer: VideoCapturer::new(),
+            capturer: VideoCapturer::new(config),
             encoder,
             pts: 0,
+            force_keyframe: false,
+            width: config.region.width,
+            height: config.region.height,
         }
     }
 
-    pub fn capture_and_encode(&mut self) -> (Vec<u8>, FrameLatencyInfo) {
+    // libx264 picks up rate control changes on the next frame
+    pub fn set_bitrate(&mut self, bitrate_kbps: u32, max_bitrate_kbps: u32) {
+        unsafe {
+            (*self.encoder).bit_rate = (bitrate_kbps as i64) << 10;
+            (*self.encoder).rc_max_rate = (max_bitrate_kbps as i64) << 10;
+            (*self.encoder).rc_buffer_size = ((max_bitrate_kbps << 10) / 60) as i32;
+        }
+    }
+
+    // Makes the next frame an IDR frame
+    pub fn request_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    // Also returns when the capture finished, on the capture's clock
+    pub fn capture_and_encode(&mut self) -> (Vec<u8>, u64, FrameLatencyInfo) {
         let (image, mut f) = self.capturer.capture_frame();
+        let captured_us = clock::now_us();
         if image.len() == 0 {
-            return (vec![], f);
+            return (vec![], captured_us, f);
         }
 
         // Allocate the RGB frame for the converted image
         let mut yuv_frame = unsafe { ffmpeg::av_frame_alloc() };
         unsafe {
             (*yuv_frame).format = ffmpeg::AVPixelFormat::AV_PIX_FMT_YUV420P as i32;
-            (*yuv_frame).width = CAPTURE_WIDTH as i32;
-            (*yuv_frame).height = CAPTURE_HEIGHT as i32;
+            (*yuv_frame).width = self.width as i32;
+            (*yuv_frame).height = self.height as i32;
         };
 
         if unsafe { ffmpeg::av_frame_get_buffer(yuv_frame, 0) } < 0 {
@@ -93,9 +120,9 @@ impl VideoEncoder {
             &mut v_plane,
             unsafe { (*yuv_frame).linesize[2] } as u32,
             &image,
-            CAPTURE_WIDTH * 4,
-            CAPTURE_WIDTH,
-            CAPTURE_HEIGHT,
+            self.width * 4,
+            self.width,
+            self.height,
             yuvutils_rs::YuvRange::Full,
             yuvutils_rs::YuvStandardMatrix::Bt709,
         );
@@ -106,6 +133,11 @@ impl VideoEncoder {
         }
         self.pts += 1;
 
+        if self.force_keyframe {
+            unsafe { (*yuv_frame).pict_type = ffmpeg::AVPictureType::AV_PICTURE_TYPE_I };
+            self.force_keyframe = false;
+        }
+
         f.measure("yuv conversion");
 
         // Encode the frame
@@ -125,7 +157,7 @@ impl VideoEncoder {
 
             if ret == -ffmpeg::EAGAIN {
                 unsafe { ffmpeg::av_packet_free(std::ptr::addr_of_mut!(pkt)) };
-                return (out, f);
+                return (out, captured_us, f);
             } else if ret < 0 {
                 panic!("failed to receive encoded packet: {}", ret);
             }
@@ -137,6 +169,6 @@ impl VideoEncoder {
         }
         f.measure("received packets");
 
-        (out, f)
+        (out, captured_us, f)
     }
 }
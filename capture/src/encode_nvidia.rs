@@ -1,14 +1,26 @@
+use std::time::{Duration, Instant};
+
+use common::clock;
 use cudarc::driver::CudaDevice;
 use nvidia_video_codec_sdk::{
     sys::nvEncodeAPI::{
-        NV_ENC_CODEC_H264_GUID, NV_ENC_MULTI_PASS, NV_ENC_PARAMS_RC_MODE, NV_ENC_PRESET_P1_GUID,
+        NV_ENC_CODEC_H264_GUID, NV_ENC_MULTI_PASS, NV_ENC_PARAMS_RC_MODE, NV_ENC_PIC_TYPE,
+        NV_ENC_PRESET_P1_GUID,
     },
     Bitstream, Buffer, EncodePictureParams, Encoder, Session,
 };
 
-use crate::{
-    ui::FrameLatencyInfo, video_capture::VideoCapturer, CAPTURE_HEIGHT, CAPTURE_WIDTH, FRAME_RATE,
-};
+use crate::{config::Config, ui::FrameLatencyInfo, video_capture::VideoCapturer};
+
+// Changing the bitrate restarts the session, which costs a new cuda context and an IDR
+// frame, so changes are rounded to a tenth of the configured bitrate and spaced out.
+// Backing off is let through sooner than probing for more
+const BITRATE_STEPS: u32 = 10;
+const RESTART_INTERVAL_DOWN: Duration = Duration::from_secs(1);
+const RESTART_INTERVAL_UP: Duration = Duration::from_secs(5);
+
+// Picture types are picked here rather than by the encoder, so keyframes can be forced
+const IDR_PERIOD: u32 = 128;
 
 pub struct VideoEncoder {
     capturer: VideoCapturer,
@@ -16,10 +28,65 @@ pub struct VideoEncoder {
     session: &'static Session,
     in_buf: Option<Buffer<'static>>,
     out_bits: Option<Bitstream<'static>>,
+
+    width: u32,
+    height: u32,
+    frame_rate: u32,
+    bitrate_kbps: u32,
+    max_bitrate_kbps: u32,
+
+    // Applied once enough time has passed since the last restart
+    target_kbps: u32,
+    target_max_kbps: u32,
+    step_kbps: u32,
+    last_restart: Instant,
+
+    force_idr: bool,
+    frames_since_idr: u32,
 }
 
 impl VideoEncoder {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
+        let session = Self::start_session(
+            config.region.width,
+            config.region.height,
+            config.frame_rate,
+            config.bitrate_kbps,
+            config.max_bitrate_kbps,
+        );
+
+        let mut e = Self {
+            capturer: VideoCapturer::new(config),
+            session,
+            in_buf: None,
+            out_bits: None,
+            width: config.region.width,
+            height: config.region.height,
+            frame_rate: config.frame_rate,
+            bitrate_kbps: config.bitrate_kbps,
+            max_bitrate_kbps: config.max_bitrate_kbps,
+            target_kbps: config.bitrate_kbps,
+            target_max_kbps: config.max_bitrate_kbps,
+            step_kbps: (config.bitrate_kbps / BITRATE_STEPS).max(1),
+            last_restart: Instant::now(),
+            force_idr: true,
+            frames_since_idr: 0,
+        };
+
+        // Create input and output buffers
+        e.in_buf = Some(session.create_input_buffer().unwrap());
+        e.out_bits = Some(session.create_output_bitstream().unwrap());
+
+        e
+    }
+
+    fn start_session(
+        width: u32,
+        height: u32,
+        frame_rate: u32,
+        bitrate_kbps: u32,
+        max_bitrate_kbps: u32,
+    ) -> &'static Session {
         // Create gpu encoder
         let cuda_device = CudaDevice::new(0).unwrap();
         let encoder = Encoder::initialize_with_cuda(cuda_device).unwrap();
@@ -31,14 +98,14 @@ impl VideoEncoder {
             nvidia_video_codec_sdk::sys::nvEncodeAPI::NV_ENC_TUNING_INFO::NV_ENC_TUNING_INFO_ULTRA_LOW_LATENCY,
         ).unwrap().presetCfg;
         enc_conf.rcParams.rateControlMode = NV_ENC_PARAMS_RC_MODE::NV_ENC_PARAMS_RC_CBR;
-        enc_conf.rcParams.maxBitRate = 12 << 20;
-        enc_conf.rcParams.averageBitRate = 8 << 20;
+        enc_conf.rcParams.maxBitRate = max_bitrate_kbps << 10;
+        enc_conf.rcParams.averageBitRate = bitrate_kbps << 10;
         enc_conf.rcParams.multiPass = NV_ENC_MULTI_PASS::NV_ENC_MULTI_PASS_DISABLED;
         enc_conf.rcParams.lowDelayKeyFrameScale = 0;
         enc_conf.rcParams.enableAQ();
         unsafe {
             enc_conf.encodeCodecConfig.h264Config.repeatSPSPPS();
-            enc_conf.encodeCodecConfig.h264Config.idrPeriod = 128;
+            enc_conf.encodeCodecConfig.h264Config.idrPeriod = IDR_PERIOD;
             enc_conf.encodeCodecConfig.h264Config.enableLTR();
             // enc_conf.encodeCodecConfig.h264Config.sliceMode = 1;
             // enc_conf.encodeCodecConfig.h264Config.sliceModeData = 1300 - 28;
@@ -47,51 +114,109 @@ impl VideoEncoder {
         let mut init_params =
             nvidia_video_codec_sdk::sys::nvEncodeAPI::NV_ENC_INITIALIZE_PARAMS::new(
                 NV_ENC_CODEC_H264_GUID,
-                CAPTURE_WIDTH,
-                CAPTURE_HEIGHT,
+                width,
+                height,
             );
         init_params.encode_config(&mut enc_conf);
-        init_params.enable_picture_type_decision();
         init_params.display_aspect_ratio(16, 9);
-        init_params.framerate(FRAME_RATE, 1);
+        init_params.framerate(frame_rate, 1);
 
         let session = encoder.start_session(
             nvidia_video_codec_sdk::sys::nvEncodeAPI::NV_ENC_BUFFER_FORMAT::NV_ENC_BUFFER_FORMAT_ARGB,
             init_params,
         ).unwrap();
 
-        let sess = Box::leak(Box::new(session));
+        Box::leak(Box::new(session))
+    }
 
-        let mut e = Self {
-            capturer: VideoCapturer::new(),
-            session: sess,
-            in_buf: None,
-            out_bits: None,
+    // Takes effect on a later frame, see BITRATE_STEPS
+    pub fn set_bitrate(&mut self, bitrate_kbps: u32, max_bitrate_kbps: u32) {
+        // Round down, so a back off is never undone by the rounding
+        let rounded = bitrate_kbps - bitrate_kbps % self.step_kbps;
+        self.target_kbps = if rounded == 0 { bitrate_kbps } else { rounded };
+        self.target_max_kbps = max_bitrate_kbps;
+    }
+
+    fn apply_bitrate(&mut self) {
+        if self.target_kbps == self.bitrate_kbps {
+            return;
+        }
+        let interval = if self.target_kbps < self.bitrate_kbps {
+            RESTART_INTERVAL_DOWN
+        } else {
+            RESTART_INTERVAL_UP
         };
+        if self.last_restart.elapsed() < interval {
+            return;
+        }
 
-        // Create input and output buffers
-        e.in_buf = Some(sess.create_input_buffer().unwrap());
-        e.out_bits = Some(sess.create_output_bitstream().unwrap());
+        self.bitrate_kbps = self.target_kbps;
+        self.max_bitrate_kbps = self.target_max_kbps;
+        self.restart();
+    }
 
-        e
+    // The sdk bindings can't reconfigure a running session, so replace it.
+    // The new session has to start with an IDR frame
+    fn restart(&mut self) {
+        // The buffers borrow the old session, so drop them before it
+        self.in_buf = None;
+        self.out_bits = None;
+        unsafe {
+            drop(Box::from_raw(
+                self.session as *const Session as *mut Session,
+            ))
+        };
+
+        self.session = Self::start_session(
+            self.width,
+            self.height,
+            self.frame_rate,
+            self.bitrate_kbps,
+            self.max_bitrate_kbps,
+        );
+        self.in_buf = Some(self.session.create_input_buffer().unwrap());
+        self.out_bits = Some(self.session.create_output_bitstream().unwrap());
+        self.last_restart = Instant::now();
+        self.force_idr = true;
     }
 
-    pub fn capture_and_encode(&mut self) -> (Vec<u8>, FrameLatencyInfo) {
+    // Makes the next frame an IDR frame
+    pub fn request_keyframe(&mut self) {
+        self.force_idr = true;
+    }
+
+    // Also returns when the capture finished, on the capture's clock
+    pub fn capture_and_encode(&mut self) -> (Vec<u8>, u64, FrameLatencyInfo) {
+        self.apply_bitrate();
+
         // Capture the image
         let (image, mut f) = self.capturer.capture_frame();
+        let captured_us = clock::now_us();
         if image.len() == 0 {
-            return (vec![], f);
+            return (vec![], captured_us, f);
         }
 
         // Encode the image, writing potentially multiple nalus
         unsafe { self.in_buf.as_mut().unwrap().lock().unwrap().write(&image) };
         f.measure("in_buf write");
 
+        let picture_type = if self.force_idr || self.frames_since_idr >= IDR_PERIOD {
+            self.force_idr = false;
+            self.frames_since_idr = 0;
+            NV_ENC_PIC_TYPE::NV_ENC_PIC_TYPE_IDR
+        } else {
+            NV_ENC_PIC_TYPE::NV_ENC_PIC_TYPE_P
+        };
+        self.frames_since_idr += 1;
+
         self.session
             .encode_picture(
                 self.in_buf.as_mut().unwrap(),
                 self.out_bits.as_mut().unwrap(),
-                EncodePictureParams::default(),
+                EncodePictureParams {
+                    picture_type,
+                    ..Default::default()
+                },
             )
             .unwrap();
         f.measure("encode");
@@ -102,6 +227,6 @@ impl VideoEncoder {
         let b = nalus.data().to_vec();
         f.measure("nalues to_vec");
 
-        (b, f)
+        (b, captured_us, f)
     }
 }
@@ -0,0 +1,381 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use common::{
+    chan::{SubChanReader, SubChanWriter},
+    msgs::{GamepadAxis, GamepadButton, GamepadEvent, GamepadFeedback},
+};
+use evdev::{
+    uinput::VirtualDevice, AbsInfo, AbsoluteAxisCode, AbsoluteAxisEvent, AttributeSet, BusType,
+    EventSummary, FFEffectCode, FFEffectKind, InputEvent, InputId, KeyCode, KeyEvent, UInputCode,
+    UinputAbsSetup,
+};
+use log::{error, info};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+pub const SUPPORTED: bool = true;
+
+// How long to wait for gamepad events before checking the pads for rumble
+const RUMBLE_POLL: Duration = Duration::from_millis(5);
+
+// Like xpad, so a display can't fill the capture with virtual devices
+const MAX_PADS: usize = 4;
+
+const STICK_MAX: i32 = 32767;
+const TRIGGER_MAX: i32 = 255;
+
+// Creates a virtual pad on the capture for each of the display's pads, and sends
+// back rumble from games
+pub fn start_gamepads(mut writer: SubChanWriter, mut reader: SubChanReader) {
+    let (tx, rx) = mpsc::channel();
+    // Stops when the connection ends
+    thread::spawn(move || {
+        while let Ok(ev) = rmp_serde::from_read::<_, GamepadEvent>(&mut reader) {
+            if tx.send(ev).is_err() {
+                return;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let mut pads: HashMap<u32, VirtualPad> = HashMap::new();
+        loop {
+            match rx.recv_timeout(RUMBLE_POLL) {
+                Ok(ev) => handle(&mut pads, ev),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            for (pad, vpad) in pads.iter_mut() {
+                let rumbles = match vpad.rumble() {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("failed to read rumble from gamepad {}: {}", pad, e);
+                        continue;
+                    }
+                };
+                for (strong, weak, duration_ms) in rumbles {
+                    let msg = GamepadFeedback::Rumble {
+                        pad: *pad,
+                        strong,
+                        weak,
+                        duration_ms,
+                    };
+                    if writer.write_all(&rmp_serde::to_vec(&msg).unwrap()).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn handle(pads: &mut HashMap<u32, VirtualPad>, ev: GamepadEvent) {
+    let result = match ev {
+        GamepadEvent::Connected { pad, name } => {
+            if pads.contains_key(&pad) {
+                return;
+            }
+            if pads.len() >= MAX_PADS {
+                error!(
+                    "ignoring gamepad {} ({:?}), only {} can be connected",
+                    pad, name, MAX_PADS
+                );
+                return;
+            }
+
+            match VirtualPad::new() {
+                Ok(mut vpad) => {
+                    let nodes = vpad.dev_nodes();
+                    info!("gamepad {} ({:?}) connected as {:?}", pad, name, nodes);
+                    pads.insert(pad, vpad);
+                }
+                Err(e) => error!("could not create a virtual gamepad for {:?}: {}", name, e),
+            }
+            return;
+        }
+        GamepadEvent::Disconnected { pad } => {
+            if pads.remove(&pad).is_some() {
+                info!("gamepad {} disconnected", pad);
+            }
+            return;
+        }
+        GamepadEvent::Button {
+            pad,
+            button,
+            pressed,
+        } => match pads.get_mut(&pad) {
+            Some(vpad) => vpad.button(button, pressed),
+            None => return,
+        },
+        GamepadEvent::Axis { pad, axis, value } => match pads.get_mut(&pad) {
+            Some(vpad) => vpad.axis(axis, value),
+            None => return,
+        },
+    };
+
+    if let Err(e) = result {
+        error!("failed to send gamepad event: {}", e);
+    }
+}
+
+// A uinput pad which looks like a wired xbox 360 pad, so games already know its layout.
+// It shows up as /dev/input/eventN and can be checked with evtest
+struct VirtualPad {
+    device: VirtualDevice,
+    // Up, down, left and right, which the pad reports as a hat
+    dpad: [bool; 4],
+    // Rumble uploaded by games by effect id, as strong and weak magnitudes and length in ms
+    effects: HashMap<i16, (u16, u16, u16)>,
+}
+
+impl VirtualPad {
+    fn new() -> io::Result<Self> {
+        let keys = AttributeSet::from_iter([
+            KeyCode::BTN_SOUTH,
+            KeyCode::BTN_EAST,
+            KeyCode::BTN_NORTH,
+            KeyCode::BTN_WEST,
+            KeyCode::BTN_TL,
+            KeyCode::BTN_TR,
+            KeyCode::BTN_SELECT,
+            KeyCode::BTN_START,
+            KeyCode::BTN_MODE,
+            KeyCode::BTN_THUMBL,
+            KeyCode::BTN_THUMBR,
+        ]);
+        let stick = AbsInfo::new(0, -STICK_MAX - 1, STICK_MAX, 16, 128, 0);
+        let trigger = AbsInfo::new(0, 0, TRIGGER_MAX, 0, 0, 0);
+        let hat = AbsInfo::new(0, -1, 1, 0, 0, 0);
+
+        let mut builder = VirtualDevice::builder()?
+            .name("Microsoft X-Box 360 pad")
+            .input_id(InputId::new(BusType::BUS_USB, 0x045e, 0x028e, 0x110))
+            .with_keys(&keys)?
+            .with_ff(&AttributeSet::from_iter([FFEffectCode::FF_RUMBLE]))?
+            .with_ff_effects_max(16);
+        for (axis, info) in [
+            (AbsoluteAxisCode::ABS_X, stick),
+            (AbsoluteAxisCode::ABS_Y, stick),
+            (AbsoluteAxisCode::ABS_RX, stick),
+            (AbsoluteAxisCode::ABS_RY, stick),
+            (AbsoluteAxisCode::ABS_Z, trigger),
+            (AbsoluteAxisCode::ABS_RZ, trigger),
+            (AbsoluteAxisCode::ABS_HAT0X, hat),
+            (AbsoluteAxisCode::ABS_HAT0Y, hat),
+        ] {
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(axis, info))?;
+        }
+        let device = builder.build()?;
+
+        // Rumble is polled between events, so reading it mustn't block
+        fcntl(device.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+        Ok(Self {
+            device,
+            dpad: [false; 4],
+            effects: HashMap::new(),
+        })
+    }
+
+    // Where the pad can be read, e.g. /dev/input/event20
+    fn dev_nodes(&mut self) -> Vec<PathBuf> {
+        self.device
+            .enumerate_dev_nodes_blocking()
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect()
+    }
+
+    fn button(&mut self, button: GamepadButton, pressed: bool) -> io::Result<()> {
+        // Like the xpad driver, x (on the left) and y (on top) are BTN_X and BTN_Y,
+        // which are the same codes as BTN_NORTH and BTN_WEST
+        let code = match button {
+            GamepadButton::South => KeyCode::BTN_SOUTH,
+            GamepadButton::East => KeyCode::BTN_EAST,
+            GamepadButton::West => KeyCode::BTN_NORTH,
+            GamepadButton::North => KeyCode::BTN_WEST,
+            GamepadButton::LeftBumper => KeyCode::BTN_TL,
+            GamepadButton::RightBumper => KeyCode::BTN_TR,
+            GamepadButton::Select => KeyCode::BTN_SELECT,
+            GamepadButton::Start => KeyCode::BTN_START,
+            GamepadButton::Mode => KeyCode::BTN_MODE,
+            GamepadButton::LeftThumb => KeyCode::BTN_THUMBL,
+            GamepadButton::RightThumb => KeyCode::BTN_THUMBR,
+            GamepadButton::DPadUp => return self.dpad(0, pressed),
+            GamepadButton::DPadDown => return self.dpad(1, pressed),
+            GamepadButton::DPadLeft => return self.dpad(2, pressed),
+            GamepadButton::DPadRight => return self.dpad(3, pressed),
+        };
+
+        self.device.emit(&[*KeyEvent::new(code, pressed as i32)])
+    }
+
+    fn dpad(&mut self, i: usize, pressed: bool) -> io::Result<()> {
+        self.dpad[i] = pressed;
+        let [up, down, left, right] = self.dpad.map(|d| d as i32);
+
+        self.device.emit(&[
+            *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_HAT0X, right - left),
+            *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_HAT0Y, down - up),
+        ])
+    }
+
+    fn axis(&mut self, axis: GamepadAxis, value: f32) -> io::Result<()> {
+        let stick = |v: f32| (v.clamp(-1., 1.) * STICK_MAX as f32) as i32;
+        let trigger = |v: f32| (v.clamp(0., 1.) * TRIGGER_MAX as f32) as i32;
+
+        // Evdev's y axes point down
+        let (code, value) = match axis {
+            GamepadAxis::LeftStickX => (AbsoluteAxisCode::ABS_X, stick(value)),
+            GamepadAxis::LeftStickY => (AbsoluteAxisCode::ABS_Y, stick(-value)),
+            GamepadAxis::RightStickX => (AbsoluteAxisCode::ABS_RX, stick(value)),
+            GamepadAxis::RightStickY => (AbsoluteAxisCode::ABS_RY, stick(-value)),
+            GamepadAxis::LeftTrigger => (AbsoluteAxisCode::ABS_Z, trigger(value)),
+            GamepadAxis::RightTrigger => (AbsoluteAxisCode::ABS_RZ, trigger(value)),
+        };
+
+        self.device.emit(&[*AbsoluteAxisEvent::new(code, value)])
+    }
+
+    // Handles effects being uploaded and played by games, returning the rumble to send
+    fn rumble(&mut self) -> io::Result<Vec<(u16, u16, u32)>> {
+        let events: Vec<InputEvent> = match self.device.fetch_events() {
+            Ok(events) => events.collect(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        let mut rumbles = vec![];
+        for event in events {
+            match event.destructure() {
+                EventSummary::UInput(event, UInputCode::UI_FF_UPLOAD, ..) => {
+                    // The kernel has already picked the effect's id
+                    let upload = self.device.process_ff_upload(event)?;
+                    let effect = upload.effect();
+                    if let FFEffectKind::Rumble {
+                        strong_magnitude,
+                        weak_magnitude,
+                    } = effect.kind
+                    {
+                        self.effects.insert(
+                            upload.effect_id(),
+                            (strong_magnitude, weak_magnitude, effect.replay.length),
+                        );
+                    }
+                }
+                EventSummary::UInput(event, UInputCode::UI_FF_ERASE, ..) => {
+                    let erase = self.device.process_ff_erase(event)?;
+                    self.effects.remove(&(erase.effect_id() as i16));
+                }
+                // Played a number of times, or stopped with 0
+                EventSummary::ForceFeedback(_, FFEffectCode(id), count) => {
+                    let Some(&(strong, weak, length)) = self.effects.get(&(id as i16)) else {
+                        continue;
+                    };
+                    match count {
+                        0 => rumbles.push((0, 0, 0)),
+                        _ => rumbles.push((strong, weak, length as u32 * count as u32)),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(rumbles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, time::Instant};
+
+    use evdev::Device;
+
+    use super::*;
+
+    // Needs write access to /dev/uinput, so it's skipped where there isn't any
+    #[test]
+    fn virtual_pad_reads_back() {
+        if OpenOptions::new().write(true).open("/dev/uinput").is_err() {
+            eprintln!("skipping, /dev/uinput can't be opened");
+            return;
+        }
+
+        let mut vpad = VirtualPad::new().unwrap();
+        let node = vpad
+            .dev_nodes()
+            .into_iter()
+            .find(|p| p.to_string_lossy().contains("event"))
+            .unwrap();
+
+        // Udev may still be setting up the node
+        let started = Instant::now();
+        let mut device = loop {
+            match Device::open(&node) {
+                Ok(device) => break device,
+                Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("{}", e),
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        assert_eq!(device.input_id().vendor(), 0x045e);
+
+        vpad.button(GamepadButton::South, true).unwrap();
+        vpad.axis(GamepadAxis::LeftStickY, 1.).unwrap();
+
+        let mut pressed = false;
+        let mut moved = false;
+        device.set_nonblocking(true).unwrap();
+        let started = Instant::now();
+        while !(pressed && moved) && started.elapsed() < Duration::from_secs(5) {
+            let events = match device.fetch_events() {
+                Ok(events) => events,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                Err(e) => panic!("{}", e),
+            };
+            for event in events {
+                match event.destructure() {
+                    EventSummary::Key(_, KeyCode::BTN_SOUTH, 1) => pressed = true,
+                    // Up on the stick is negative in evdev
+                    EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_Y, v) => {
+                        assert_eq!(v, -STICK_MAX);
+                        moved = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        assert!(pressed && moved);
+    }
+
+    #[test]
+    fn pads_are_capped() {
+        if OpenOptions::new().write(true).open("/dev/uinput").is_err() {
+            eprintln!("skipping, /dev/uinput can't be opened");
+            return;
+        }
+
+        let mut pads = HashMap::new();
+        for pad in 0..MAX_PADS as u32 + 2 {
+            handle(
+                &mut pads,
+                GamepadEvent::Connected {
+                    pad,
+                    name: "test".into(),
+                },
+            );
+        }
+        assert_eq!(pads.len(), MAX_PADS);
+    }
+}
@@ -0,0 +1,16 @@
+use common::{
+    chan::{SubChanReader, SubChanWriter},
+    msgs::GamepadEvent,
+};
+use log::info;
+
+pub const SUPPORTED: bool = false;
+
+// Windows has no virtual gamepads without a driver, so the events are dropped
+pub fn start_gamepads(_writer: SubChanWriter, mut reader: SubChanReader) {
+    std::thread::spawn(move || {
+        while let Ok(ev) = rmp_serde::from_read::<_, GamepadEvent>(&mut reader) {
+            info!("gamepads aren't supported on windows, ignoring {:?}", ev);
+        }
+    });
+}
@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+
+use common::msgs::{KeyCode, Modifiers};
+use enigo::{Direction, Enigo, Keyboard};
+use log::info;
+
+// Presses physical keys on the capture, keeping its modifiers in step with the display's
+pub struct KeyInjector {
+    pressed: HashSet<KeyCode>,
+}
+
+impl KeyInjector {
+    pub fn new() -> Self {
+        Self {
+            pressed: HashSet::new(),
+        }
+    }
+
+    pub fn key(&mut self, enigo: &mut Enigo, code: KeyCode, modifiers: Modifiers, state: bool) {
+        // Release modifiers the display no longer holds, e.g. if it lost focus
+        // before the release was sent
+        let stale: Vec<KeyCode> = self
+            .pressed
+            .iter()
+            .filter(|k| **k != code && modifier_held(**k, modifiers) == Some(false))
+            .copied()
+            .collect();
+        for k in stale {
+            self.send(enigo, k, false);
+        }
+
+        self.send(enigo, code, state);
+    }
+
+    pub fn release_all(&mut self, enigo: &mut Enigo) {
+        let pressed: Vec<KeyCode> = self.pressed.iter().copied().collect();
+        for k in pressed {
+            self.send(enigo, k, false);
+        }
+    }
+
+    fn send(&mut self, enigo: &mut Enigo, code: KeyCode, state: bool) {
+        let direction = match state {
+            true => Direction::Press,
+            false => Direction::Release,
+        };
+        if let Err(e) = enigo.raw(raw_keycode(code), direction) {
+            info!("failed to press {:?}: {}", code, e);
+            return;
+        }
+
+        match state {
+            true => self.pressed.insert(code),
+            false => self.pressed.remove(&code),
+        };
+    }
+}
+
+// Whether the modifiers say this key should be held, or None if it isn't a modifier
+fn modifier_held(code: KeyCode, modifiers: Modifiers) -> Option<bool> {
+    match code {
+        KeyCode::ShiftLeft | KeyCode::ShiftRight => Some(modifiers.shift),
+        KeyCode::ControlLeft | KeyCode::ControlRight => Some(modifiers.ctrl),
+        KeyCode::AltLeft | KeyCode::AltRight => Some(modifiers.alt),
+        KeyCode::SuperLeft | KeyCode::SuperRight => Some(modifiers.meta),
+        _ => None,
+    }
+}
+
+// X11 keycodes are offset from the kernel's by 8
+#[cfg(target_os = "linux")]
+fn raw_keycode(code: KeyCode) -> u16 {
+    codes(code).0 + 8
+}
+
+// Enigo sends keycodes above 0x7f as extended scancodes
+#[cfg(target_os = "windows")]
+fn raw_keycode(code: KeyCode) -> u16 {
+    codes(code).1
+}
+
+// The linux input event code and the PC scancode of each key.
+// Scancodes starting with 0xe0 are extended
+fn codes(code: KeyCode) -> (u16, u16) {
+    match code {
+        KeyCode::Escape => (1, 0x01),
+        KeyCode::F1 => (59, 0x3b),
+        KeyCode::F2 => (60, 0x3c),
+        KeyCode::F3 => (61, 0x3d),
+        KeyCode::F4 => (62, 0x3e),
+        KeyCode::F5 => (63, 0x3f),
+        KeyCode::F6 => (64, 0x40),
+        KeyCode::F7 => (65, 0x41),
+        KeyCode::F8 => (66, 0x42),
+        KeyCode::F9 => (67, 0x43),
+        KeyCode::F10 => (68, 0x44),
+        KeyCode::F11 => (87, 0x57),
+        KeyCode::F12 => (88, 0x58),
+        KeyCode::PrintScreen => (99, 0xe037),
+        KeyCode::ScrollLock => (70, 0x46),
+
+        KeyCode::Backquote => (41, 0x29),
+        KeyCode::Digit1 => (2, 0x02),
+        KeyCode::Digit2 => (3, 0x03),
+        KeyCode::Digit3 => (4, 0x04),
+        KeyCode::Digit4 => (5, 0x05),
+        KeyCode::Digit5 => (6, 0x06),
+        KeyCode::Digit6 => (7, 0x07),
+        KeyCode::Digit7 => (8, 0x08),
+        KeyCode::Digit8 => (9, 0x09),
+        KeyCode::Digit9 => (10, 0x0a),
+        KeyCode::Digit0 => (11, 0x0b),
+        KeyCode::Minus => (12, 0x0c),
+        KeyCode::Equal => (13, 0x0d),
+        KeyCode::Backspace => (14, 0x0e),
+
+        KeyCode::Tab => (15, 0x0f),
+        KeyCode::KeyQ => (16, 0x10),
+        KeyCode::KeyW => (17, 0x11),
+        KeyCode::KeyE => (18, 0x12),
+        KeyCode::KeyR => (19, 0x13),
+        KeyCode::KeyT => (20, 0x14),
+        KeyCode::KeyY => (21, 0x15),
+        KeyCode::KeyU => (22, 0x16),
+        KeyCode::KeyI => (23, 0x17),
+        KeyCode::KeyO => (24, 0x18),
+        KeyCode::KeyP => (25, 0x19),
+        KeyCode::BracketLeft => (26, 0x1a),
+        KeyCode::BracketRight => (27, 0x1b),
+        KeyCode::Backslash => (43, 0x2b),
+
+        KeyCode::CapsLock => (58, 0x3a),
+        KeyCode::KeyA => (30, 0x1e),
+        KeyCode::KeyS => (31, 0x1f),
+        KeyCode::KeyD => (32, 0x20),
+        KeyCode::KeyF => (33, 0x21),
+        KeyCode::KeyG => (34, 0x22),
+        KeyCode::KeyH => (35, 0x23),
+        KeyCode::KeyJ => (36, 0x24),
+        KeyCode::KeyK => (37, 0x25),
+        KeyCode::KeyL => (38, 0x26),
+        KeyCode::Semicolon => (39, 0x27),
+        KeyCode::Quote => (40, 0x28),
+        KeyCode::Enter => (28, 0x1c),
+
+        KeyCode::ShiftLeft => (42, 0x2a),
+        KeyCode::IntlBackslash => (86, 0x56),
+        KeyCode::KeyZ => (44, 0x2c),
+        KeyCode::KeyX => (45, 0x2d),
+        KeyCode::KeyC => (46, 0x2e),
+        KeyCode::KeyV => (47, 0x2f),
+        KeyCode::KeyB => (48, 0x30),
+        KeyCode::KeyN => (49, 0x31),
+        KeyCode::KeyM => (50, 0x32),
+        KeyCode::Comma => (51, 0x33),
+        KeyCode::Period => (52, 0x34),
+        KeyCode::Slash => (53, 0x35),
+        KeyCode::ShiftRight => (54, 0x36),
+
+        KeyCode::ControlLeft => (29, 0x1d),
+        KeyCode::SuperLeft => (125, 0xe05b),
+        KeyCode::AltLeft => (56, 0x38),
+        KeyCode::Space => (57, 0x39),
+        KeyCode::AltRight => (100, 0xe038),
+        KeyCode::SuperRight => (126, 0xe05c),
+        KeyCode::ContextMenu => (127, 0xe05d),
+        KeyCode::ControlRight => (97, 0xe01d),
+
+        KeyCode::Insert => (110, 0xe052),
+        KeyCode::Delete => (111, 0xe053),
+        KeyCode::Home => (102, 0xe047),
+        KeyCode::End => (107, 0xe04f),
+        KeyCode::PageUp => (104, 0xe049),
+        KeyCode::PageDown => (109, 0xe051),
+        KeyCode::ArrowUp => (103, 0xe048),
+        KeyCode::ArrowDown => (108, 0xe050),
+        KeyCode::ArrowLeft => (105, 0xe04b),
+        KeyCode::ArrowRight => (106, 0xe04d),
+
+        KeyCode::NumLock => (69, 0x45),
+        KeyCode::NumpadDivide => (98, 0xe035),
+        KeyCode::NumpadMultiply => (55, 0x37),
+        KeyCode::NumpadSubtract => (74, 0x4a),
+        KeyCode::NumpadAdd => (78, 0x4e),
+        KeyCode::NumpadEnter => (96, 0xe01c),
+        KeyCode::NumpadDecimal => (83, 0x53),
+        KeyCode::Numpad0 => (82, 0x52),
+        KeyCode::Numpad1 => (79, 0x4f),
+        KeyCode::Numpad2 => (80, 0x50),
+        KeyCode::Numpad3 => (81, 0x51),
+        KeyCode::Numpad4 => (75, 0x4b),
+        KeyCode::Numpad5 => (76, 0x4c),
+        KeyCode::Numpad6 => (77, 0x4d),
+        KeyCode::Numpad7 => (71, 0x47),
+        KeyCode::Numpad8 => (72, 0x48),
+        KeyCode::Numpad9 => (73, 0x49),
+    }
+}
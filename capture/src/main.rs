@@ -1,12 +1,21 @@
 #![feature(thread_sleep_until)]
 
+mod approval;
 mod audio_encode;
+mod config;
+mod congestion;
+mod keymap;
+mod mouse;
 mod ui;
 
 #[cfg_attr(target_os = "linux", path = "audio_linux.rs")]
 #[cfg_attr(target_os = "windows", path = "audio_windows.rs")]
 mod audio_capture;
 
+#[cfg_attr(target_os = "linux", path = "gamepad_linux.rs")]
+#[cfg_attr(target_os = "windows", path = "gamepad_windows.rs")]
+mod gamepad;
+
 #[cfg_attr(
     all(target_os = "linux", feature = "wayland"),
     path = "capture_wayland.rs"
@@ -24,211 +33,647 @@ mod udp;
 #[cfg_attr(not(feature = "nvenc"), path = "encode_ffmpeg.rs")]
 mod video_encode;
 
-use common::chan;
-use common::msgs::{KeyEvent, RTMsg};
+use common::chan::{self, ChanState};
+use common::clipboard;
+use common::clock;
+use common::filetransfer;
+use common::handshake::{
+    self, AudioFormat, Codec, HandshakeError, Negotiated, SessionHello, VideoFormat, FEATURE_AUDIO,
+    FEATURE_CLIPBOARD, FEATURE_FILE_TRANSFER, FEATURE_GAMEPAD, FEATURE_PORT_FORWARD,
+    PROTOCOL_VERSION,
+};
+use common::msgs::{
+    AudioHeader, ControlMsg, Feedback, FrameHeader, GamepadEvent, KeyEvent, UDP_HEARTBEAT_INTERVAL,
+    UDP_TIMEOUT,
+};
 use common::portforward::PortForwarder;
+use common::repeater::{self, Backoff, Hello, Role, TcpHello};
+use common::secure::{self, PairingCode, PeerIdentity, SecureChannel, SecureError};
+use std::io::{self, Write};
 use std::net::{TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::{self, sleep, sleep_until};
+use std::thread::{self, sleep, sleep_until, JoinHandle};
 use std::time::{Duration, Instant};
 
+use approval::{Access, TrustList};
 use audio_encode::AudioEncoder;
+use config::Config;
+use congestion::CongestionController;
 use enigo::{Enigo, Keyboard, Mouse, Settings};
+use keymap::KeyInjector;
+use mouse::Scroller;
 
-use log::info;
+use log::{error, info};
 use udp::UdpStream;
-use ui::FrameLatencyInfo;
+use ui::{FrameLatencyInfo, UI};
 use video_encode::VideoEncoder;
 
-// const FRAME_DURATION: Duration = Duration::from_micros(16_666);
-// const FRAME_RATE: u32 = 60;
-const FRAME_DURATION: Duration = Duration::from_micros(100_000);
-const FRAME_RATE: u32 = 10;
-
-const CAPTURE_WIDTH: u32 = 2560;
-const CAPTURE_HEIGHT: u32 = 1440;
-// const CAPTURE_WIDTH: u32 = 2256;
-// const CAPTURE_HEIGHT: u32 = 1504;
-const CAPTURE_OFFSET_X: u32 = 3840;
-const CAPTURE_OFFSET_Y: u32 = 240;
-
 pub struct Capturer {
     audio: AudioEncoder,
     video: VideoEncoder,
 }
 
-pub fn new_encoder() -> Capturer {
+pub fn new_encoder(config: &Config) -> Capturer {
     // info!("capture starting");
 
-    let audio = AudioEncoder::new();
-    let video = VideoEncoder::new();
+    let audio = AudioEncoder::new(config);
+    let video = VideoEncoder::new(config);
 
     Capturer { audio, video }
 }
 
+// Why connecting to the display failed
+enum ConnectError {
+    // Lost the repeater or the display on the way, so try again
+    Retry(String),
+    // The ui was closed, or the display can't be let in
+    Quit,
+}
+
+impl From<io::Error> for ConnectError {
+    fn from(e: io::Error) -> Self {
+        ConnectError::Retry(e.to_string())
+    }
+}
+
+// A display which has been authenticated and let in
+struct Connection {
+    sock: UdpSocket,
+    chan: chan::TcpChan,
+    secure: Arc<SecureChannel>,
+    peer: PeerIdentity,
+    access: Access,
+    negotiated: Negotiated,
+}
+
 fn main() {
-    let (ui, ui_thread) = ui::start_ui();
+    let config = Config::load();
+
+    let clipboard = Arc::new(AtomicBool::new(config.clipboard));
+    let (ui, ui_thread) = ui::start_ui(clipboard.clone());
     log::set_boxed_logger(Box::new(ui::Logger(ui.clone()))).unwrap();
     log::set_max_level(log::LevelFilter::Info);
 
-    let mut enc = new_encoder();
-    info!("waiting for a display client");
+    let mut enc = new_encoder(&config);
+
+    // The secret half of the code never goes through the repeater
+    let code = PairingCode::new(
+        config
+            .session
+            .clone()
+            .unwrap_or_else(repeater::new_session_code),
+    );
+    info!("waiting for a display client with pairing code {}", code);
+
+    let mut trust = TrustList::load(&config.trust_file);
+    // The display let in last, which is let back in without asking if it reconnects
+    let mut last_display: Option<(PeerIdentity, Access)> = None;
+    // Given by the repeater when last paired, proving it's us pairing again
+    let mut token = None;
+    // Kept across reconnections, so forwarded ports keep listening
+    let mut portforwarder: Option<(Arc<Mutex<chan::TcpChan>>, PortForwarder)> = None;
+    let mut usb_listening = false;
+    let mut backoff = Backoff::default();
+    let target_bitrate = Arc::new(AtomicU32::new(config.bitrate_kbps));
+    let mut bitrate = config.bitrate_kbps;
+    ui.lock().unwrap().set_bitrate(bitrate);
+    let mut f = FrameLatencyInfo::new();
+    let mut frame_id = 0;
+    let mut audio_seq = 0;
+    loop {
+        let conn = match connect(
+            &config,
+            &code,
+            &ui,
+            &ui_thread,
+            &mut trust,
+            &last_display,
+            &mut token,
+        ) {
+            Ok(conn) => conn,
+            Err(ConnectError::Retry(e)) => {
+                let delay = backoff.next_delay();
+                error!(
+                    "could not connect to the display: {}, trying again in {:?}",
+                    e, delay
+                );
+                if !wait(delay, &ui_thread) {
+                    return;
+                }
+                continue;
+            }
+            Err(ConnectError::Quit) => {
+                ui_thread.join().unwrap();
+                return;
+            }
+        };
+        backoff.reset();
+        last_display = Some((conn.peer.clone(), conn.access));
+        let Connection {
+            sock,
+            chan,
+            secure,
+            access,
+            negotiated,
+            ..
+        } = conn;
+
+        let ksock = sock.try_clone().unwrap();
+        let ustream = Arc::new(Mutex::new(UdpStream::new(
+            sock,
+            secure.clone(),
+            config.fec_group_size,
+        )));
+
+        // Stop streaming once the display goes away, and wait for it to come back
+        chan.on_close(|state| error!("display disconnected: {}", state));
+
+        let mut key_chan = chan.create_subchan(chan::ChannelId::Keys).1;
+        let (mut control_writer, mut control_chan) = chan.create_subchan(chan::ChannelId::Control);
+        // Decided again for each display, as view only ones can't reach anything
+        // through the capture or be reached from it
+        let forward = negotiated.features & FEATURE_PORT_FORWARD != 0 && access == Access::Input;
+        match &portforwarder {
+            Some((master, p)) => {
+                *master.lock().unwrap() = chan.clone();
+                p.resume(forward);
+            }
+            None => {
+                let master = Arc::new(Mutex::new(chan.clone()));
+                let p = PortForwarder::new(master.clone(), forward);
+                portforwarder = Some((master, p));
+            }
+        }
+        // Usb devices shared by the display, only listened for once one can use them
+        if forward && !usb_listening {
+            let (_, p) = portforwarder.as_ref().unwrap();
+            // Tried again with the next display if the port is taken
+            match p.listen_and_forward("127.0.0.1:3240".parse().unwrap(), "127.0.0.1:3240".into()) {
+                Ok(()) => usb_listening = true,
+                Err(e) => error!("could not listen for usb devices: {}", e),
+            }
+        }
 
-    let mut sock = UdpSocket::bind("0.0.0.0:0").unwrap();
-    sock.connect("dw.superkooks.com:42069").unwrap();
-    sock.send(&vec![0]).unwrap();
-    let ksock = sock.try_clone().unwrap();
+        if negotiated.features & FEATURE_GAMEPAD != 0 {
+            let (gamepad_writer, mut gamepad_chan) = chan.create_subchan(chan::ChannelId::Gamepad);
+            match access {
+                Access::Input => gamepad::start_gamepads(gamepad_writer, gamepad_chan),
+                // Still read them so they don't pile up
+                Access::ViewOnly => {
+                    thread::spawn(move || {
+                        while rmp_serde::from_read::<_, GamepadEvent>(&mut gamepad_chan).is_ok() {}
+                    });
+                }
+            }
+        }
 
-    let net_hand = thread::spawn(move || {
-        sock.recv(&mut vec![0]).unwrap();
-        sock
-    });
-    loop {
-        if net_hand.is_finished() {
-            sock = net_hand.join().unwrap();
-            break;
-        } else if ui_thread.is_finished() {
-            return;
+        if negotiated.features & FEATURE_CLIPBOARD != 0 {
+            let (clipboard_writer, mut clipboard_chan) =
+                chan.create_subchan(chan::ChannelId::Clipboard);
+            match access {
+                Access::Input => {
+                    if let Err(e) = clipboard::start_clipboard_sync(
+                        clipboard_writer,
+                        clipboard_chan,
+                        clipboard.clone(),
+                    ) {
+                        error!("could not share the clipboard: {}", e);
+                    }
+                }
+                // Still read them so they don't pile up, without holding whole images
+                Access::ViewOnly => {
+                    thread::spawn(move || io::copy(&mut clipboard_chan, &mut io::sink()));
+                }
+            }
         }
-    }
 
-    let ustream = Arc::new(Mutex::new(UdpStream::new(sock)));
+        // View only displays are told they can't send files
+        if negotiated.features & FEATURE_FILE_TRANSFER != 0 {
+            let (files_writer, files_chan) = chan.create_subchan(chan::ChannelId::FileTransfer);
+            filetransfer::start_file_receiver(
+                files_writer,
+                files_chan,
+                config.received_dir.clone(),
+                access == Access::Input,
+            );
+        }
 
-    let tcp_sock = TcpStream::connect("dw.superkooks.com:42069").unwrap();
-    let master_chan = Arc::new(Mutex::new(chan::TcpChan::new(tcp_sock)));
-    let mut key_chan = master_chan
-        .lock()
-        .unwrap()
-        .create_subchan(chan::ChannelId::Keys)
-        .1;
-    let portforwarder = PortForwarder::new(master_chan.clone());
-    portforwarder.listen_and_forward("127.0.0.1:3240".parse().unwrap(), "127.0.0.1:3240".into());
+        // Forward keyboard events to application
+        let origin = config.capture_origin();
+        if origin.is_none() {
+            error!("absolute mouse mode only works when capturing the primary monitor");
+        }
+        thread::spawn(move || {
+            // Still read them so they don't pile up
+            if access == Access::ViewOnly {
+                while rmp_serde::from_read::<_, KeyEvent>(&mut key_chan).is_ok() {}
+                return;
+            }
 
-    // Forward keyboard events to application
-    thread::spawn(move || {
-        let mut enigo = Enigo::new(&Settings::default()).unwrap();
-        // let mut t = Instant::now();
+            let mut enigo = Enigo::new(&Settings::default()).unwrap();
+            let mut keys = KeyInjector::new();
+            let mut scroller = Scroller::default();
+            // let mut t = Instant::now();
+
+            // Stops when the connection ends
+            while let Ok(ev) = rmp_serde::from_read(&mut key_chan) {
+                match ev {
+                    KeyEvent::Key { letter, state } => {
+                        enigo
+                            .key(
+                                enigo::Key::Unicode(letter),
+                                match state {
+                                    true => enigo::Direction::Press,
+                                    false => enigo::Direction::Release,
+                                },
+                            )
+                            .unwrap();
+                    }
+                    KeyEvent::Physical {
+                        code,
+                        modifiers,
+                        state,
+                    } => keys.key(&mut enigo, code, modifiers, state),
+                    KeyEvent::Click { button, state } => {
+                        let Some(b) = mouse::button(button) else {
+                            info!("can't press mouse button {:?}", button);
+                            continue;
+                        };
+
+                        let direction = match state {
+                            true => enigo::Direction::Press,
+                            false => enigo::Direction::Release,
+                        };
+                        if let Err(e) = enigo.button(b, direction) {
+                            info!("failed to press mouse button {:?}: {}", button, e);
+                        }
+                    }
+                    KeyEvent::Scroll { delta } => scroller.scroll(&mut enigo, delta),
+                    KeyEvent::MouseAbs { x, y } => {
+                        let Some(origin) = origin else {
+                            continue;
+                        };
+                        if let Err(e) = enigo.move_mouse(
+                            origin.0 + x as i32,
+                            origin.1 + y as i32,
+                            enigo::Coordinate::Abs,
+                        ) {
+                            info!("failed to move mouse: {}", e);
+                        }
+                    }
+                    KeyEvent::Mouse { x, y } => {
+                        // println!("{} {}", x, y);
+                        // println!(
+                        //     "last mouse {} us ago",
+                        //     Instant::now().duration_since(t).as_micros()
+                        // );
+                        // t = Instant::now();
+                        enigo
+                            .move_mouse(x as i32, y as i32, enigo::Coordinate::Rel)
+                            .unwrap();
+                    }
+                }
+            }
 
-        loop {
-            let ev = rmp_serde::from_read(&mut key_chan).unwrap();
-            match ev {
-                KeyEvent::Key { letter, state } => {
-                    enigo
-                        .key(
-                            enigo::Key::Unicode(letter),
-                            match state {
-                                true => enigo::Direction::Press,
-                                false => enigo::Direction::Release,
-                            },
-                        )
-                        .unwrap();
+            // Don't leave keys held down while the display is gone
+            keys.release_all(&mut enigo);
+        });
+
+        // Read control messages from the display
+        let keyframe_requested = Arc::new(AtomicBool::new(false));
+        let kkeyframe_requested = keyframe_requested.clone();
+        let kui = ui.clone();
+        thread::spawn(move || loop {
+            let Ok(msg) = rmp_serde::from_read::<_, ControlMsg>(&mut control_chan) else {
+                return;
+            };
+            match msg {
+                ControlMsg::RequestKeyframe => kkeyframe_requested.store(true, Ordering::Relaxed),
+                ControlMsg::Ping { sent_us } => {
+                    let received_us = clock::now_us();
+                    let pong = ControlMsg::Pong {
+                        ping_sent_us: sent_us,
+                        received_us,
+                        sent_us: clock::now_us(),
+                    };
+                    if control_writer
+                        .write_all(&rmp_serde::to_vec(&pong).unwrap())
+                        .is_err()
+                    {
+                        return;
+                    }
                 }
-                KeyEvent::Click { button, state } => {
-                    enigo
-                        .button(
-                            match button {
-                                0 => enigo::Button::Left,
-                                1 => enigo::Button::Middle,
-                                2 => enigo::Button::Right,
-                                _ => panic!("invalid button"),
-                            },
-                            match state {
-                                true => enigo::Direction::Press,
-                                false => enigo::Direction::Release,
-                            },
-                        )
-                        .unwrap();
+                ControlMsg::Latency(report) => kui.lock().unwrap().set_latency(report),
+                ControlMsg::Pong { .. } => {}
+            }
+        });
+
+        // Spawn thread to read nacks and receiver reports, until the connection ends
+        let kustream = ustream.clone();
+        let kchan = chan.clone();
+        let ktarget_bitrate = target_bitrate.clone();
+        let mut congestion = CongestionController::new(&config);
+        let adaptive_bitrate = config.adaptive_bitrate;
+        let feedback_thread = thread::spawn(move || {
+            // Wake up regularly to check the display is still there
+            ksock
+                .set_read_timeout(Some(UDP_HEARTBEAT_INTERVAL))
+                .unwrap();
+            let mut last_heard = Instant::now();
+            while kchan.state() == ChanState::Open {
+                if last_heard.elapsed() > UDP_TIMEOUT {
+                    kchan.fail(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "nothing heard from the display over udp for {:?}",
+                            UDP_TIMEOUT
+                        ),
+                    ));
+                    return;
                 }
-                KeyEvent::Mouse { x, y } => {
-                    // println!("{} {}", x, y);
-                    // println!(
-                    //     "last mouse {} us ago",
-                    //     Instant::now().duration_since(t).as_micros()
-                    // );
-                    // t = Instant::now();
-                    enigo
-                        .move_mouse(x as i32, y as i32, enigo::Coordinate::Rel)
-                        .unwrap();
+
+                let mut buf = vec![0; 2048];
+                let Ok(size) = ksock.recv(&mut buf) else {
+                    continue;
+                };
+
+                let buf = match secure.open_udp(&buf[..size]) {
+                    Ok(buf) => buf,
+                    Err(e) => {
+                        info!("dropped feedback from display: {}", e);
+                        continue;
+                    }
+                };
+                let msg: Feedback = match rmp_serde::from_slice(&buf) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        info!("invalid feedback from display: {}", e);
+                        continue;
+                    }
+                };
+                last_heard = Instant::now();
+                match msg {
+                    Feedback::Nack { seq } => kustream.lock().unwrap().process_nack(seq),
+                    Feedback::Report(report) => {
+                        if !adaptive_bitrate {
+                            continue;
+                        }
+
+                        if let Some(bitrate) = congestion.on_report(&report) {
+                            ktarget_bitrate.store(bitrate, Ordering::Relaxed);
+                        }
+                    }
+                    Feedback::Heartbeat => {}
                 }
             }
-        }
-    });
+        });
 
-    // Spawn thread to read nacks
-    let kustream = ustream.clone();
-    thread::spawn(move || loop {
-        let mut buf = vec![0; 2048];
-        ksock.recv(&mut buf).unwrap();
+        sleep(Duration::from_millis(100));
+        info!("got display client");
 
-        let msg: RTMsg = rmp_serde::from_slice(&buf).unwrap();
-        kustream.lock().unwrap().process_nack(msg.seq);
-    });
+        // Begin capturing, starting the new display off with a keyframe
+        enc.audio.source.uncork();
+        enc.video.request_keyframe();
 
-    sleep(Duration::from_millis(100));
-    info!("got display client");
+        loop {
+            let loop_start = Instant::now();
+            let mut main_fli = FrameLatencyInfo::new();
 
-    // Begin capturing
-    enc.audio.source.uncork();
+            if ui_thread.is_finished() {
+                return;
+            }
+            if chan.state() != ChanState::Open {
+                break;
+            }
 
-    let mut f = FrameLatencyInfo::new();
-    loop {
-        let loop_start = Instant::now();
-        let mut main_fli = FrameLatencyInfo::new();
+            // Apply the bitrate chosen by congestion control
+            let target = target_bitrate.load(Ordering::Relaxed);
+            if target != bitrate {
+                bitrate = target;
+                enc.video
+                    .set_bitrate(bitrate, config.max_bitrate_for(bitrate));
+                ui.lock().unwrap().set_bitrate(bitrate);
+            }
 
-        if ui_thread.is_finished() {
-            return;
-        }
+            if keyframe_requested.swap(false, Ordering::Relaxed) {
+                info!("display requested a keyframe");
+                enc.video.request_keyframe();
+            }
 
-        // Video
-        // println!("capturing...");
-        // let mut t = Instant::now();
-        let (nalus, fli) = enc.video.capture_and_encode();
-        main_fli.measure("capture");
-        ui.lock().unwrap().add_frame_latency_info("frame", fli);
-        main_fli.measure("ui frame fli");
-        // println!(
-        //     "captured image after {} us",
-        //     Instant::now().duration_since(t).as_micros()
-        // );
-        // t = Instant::now();
-
-        // Packetize the nalus into mtu sized blocks
-        let chunks: Vec<&[u8]> = nalus.chunks(1400).collect();
-        for chunk in chunks {
-            ustream.lock().unwrap().send_packet(chunk.into(), false);
-
-            f.measure("last_packet");
-            if f.total() > 2500 {
-                ui.lock().unwrap().add_frame_latency_info("packet", f);
-                f = FrameLatencyInfo::new();
-            }
-            // conn.write_all(&buf).unwrap();
-            // println!("sent video packet");
+            // Video
+            // println!("capturing...");
+            // let mut t = Instant::now();
+            let capture_start_us = clock::now_us();
+            let (nalus, captured_us, fli) = enc.video.capture_and_encode();
+            let encoded_us = clock::now_us();
+            main_fli.measure("capture");
+            ui.lock().unwrap().add_frame_latency_info("frame", fli);
+            main_fli.measure("ui frame fli");
             // println!(
-            //     "last video {} us ago",
-            //     Instant::now().duration_since(last_video).as_micros()
+            //     "captured image after {} us",
+            //     Instant::now().duration_since(t).as_micros()
             // );
+            // t = Instant::now();
+
+            // Packetize the nalus into mtu sized blocks
+            let chunks: Vec<&[u8]> = nalus.chunks(1400).collect();
+            let keyframe = udp::is_keyframe(&nalus);
+            for (i, chunk) in chunks.iter().enumerate() {
+                let header = FrameHeader {
+                    frame_id,
+                    fragment: i as u16,
+                    fragment_count: chunks.len() as u16,
+                    keyframe,
+                    timestamp_us: capture_start_us,
+                    captured_us,
+                    encoded_us,
+                };
+                ustream
+                    .lock()
+                    .unwrap()
+                    .send_packet(chunk.to_vec(), Some(header), None);
+
+                f.measure("last_packet");
+                if f.total() > 2500 {
+                    ui.lock().unwrap().add_frame_latency_info("packet", f);
+                    f = FrameLatencyInfo::new();
+                }
+                // conn.write_all(&buf).unwrap();
+                // println!("sent video packet");
+                // println!(
+                //     "last video {} us ago",
+                //     Instant::now().duration_since(last_video).as_micros()
+                // );
+            }
+            if !chunks.is_empty() {
+                frame_id += 1;
+            }
+            main_fli.measure("packetize video");
+
+            // Audio
+            let audio_captured_us = clock::now_us();
+            let packet = enc.audio.capture_and_encode();
+            main_fli.measure("capture audio");
+            if packet.is_some() && negotiated.features & FEATURE_AUDIO != 0 {
+                let header = AudioHeader {
+                    seq: audio_seq,
+                    timestamp_us: audio_captured_us,
+                };
+                ustream
+                    .lock()
+                    .unwrap()
+                    .send_packet(packet.unwrap(), None, Some(header));
+                audio_seq += 1;
+                // println!("sent audio packet");
+                // println!(
+                //     "last audio {} us ago",
+                //     Instant::now().duration_since(last_audio).as_micros()
+                // );
+                // last_audio = Instant::now();
+            }
+            main_fli.measure("packetize audio");
+
+            ustream.lock().unwrap().flush_fec();
+            ustream.lock().unwrap().keep_alive();
+
+            sleep_until(loop_start + config.frame_duration());
+            main_fli.measure("sleep");
+            ui.lock()
+                .unwrap()
+                .add_frame_latency_info("main_loop", main_fli);
         }
-        main_fli.measure("packetize video");
-
-        // Audio
-        let packet = enc.audio.capture_and_encode();
-        main_fli.measure("capture audio");
-        if packet.is_some() {
-            ustream.lock().unwrap().send_packet(packet.unwrap(), true);
-            // println!("sent audio packet");
-            // println!(
-            //     "last audio {} us ago",
-            //     Instant::now().duration_since(last_audio).as_micros()
-            // );
-            // last_audio = Instant::now();
+
+        // Stop capturing until the display is back
+        enc.audio.source.cork();
+        feedback_thread.join().unwrap();
+        info!("waiting for the display to reconnect");
+    }
+}
+
+// Pairs with a display through the repeater, then authenticates it and lets it in
+fn connect(
+    config: &Config,
+    code: &PairingCode,
+    ui: &Arc<Mutex<UI>>,
+    ui_thread: &JoinHandle<()>,
+    trust: &mut TrustList,
+    last_display: &Option<(PeerIdentity, Access)>,
+    token: &mut Option<u64>,
+) -> Result<Connection, ConnectError> {
+    let hello = Hello {
+        session: code.session.clone(),
+        role: Role::Capture,
+        resume: *token,
+    };
+
+    // A new socket each time, as the repeater passes on everything from one it has
+    // already paired
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    sock.connect(&config.server)?;
+    let psock = sock.try_clone()?;
+    let phello = hello.clone();
+    let net_hand = thread::spawn(move || repeater::pair(&psock, &phello));
+    while !net_hand.is_finished() {
+        if ui_thread.is_finished() {
+            return Err(ConnectError::Quit);
         }
-        main_fli.measure("packetize audio");
+        sleep(Duration::from_millis(100));
+    }
+    let paired = net_hand.join().unwrap()?;
+    // Lets the next attempt take over from this one, even if it fails from here on
+    *token = Some(paired.token);
+
+    let mut tcp_sock = TcpStream::connect(&config.server)?;
+    let tcp_hello = TcpHello {
+        session: hello.session,
+        role: hello.role,
+        token: paired.token,
+    };
+    tcp_sock.write_all(&rmp_serde::to_vec(&tcp_hello).unwrap())?;
+
+    // Authenticate the display and agree on the keys for everything sent after this.
+    // Whoever failed may not have been the display, so keep waiting for it
+    let (secure, peer) = match secure::accept(&mut tcp_sock, code) {
+        Ok((secure, peer)) => (Arc::new(secure), peer),
+        Err(SecureError::Io(e)) => return Err(e.into()),
+        Err(e) => return Err(ConnectError::Retry(e.to_string())),
+    };
+
+    // Its heartbeats keep the display waiting while it's being let in
+    let chan = chan::TcpChan::new_secure(tcp_sock, secure.clone());
+
+    // Nothing from the display is acted on until it has been let in
+    let access = match last_display {
+        Some((last, access)) if last.fingerprint == peer.fingerprint => {
+            info!("display {:?} reconnected", peer.name);
+            Some(*access)
+        }
+        _ => approval::approve(&peer, trust, ui, ui_thread),
+    };
+    let Some(access) = access else {
+        error!("display was not let in, restart to pair again");
+        chan.close();
+        return Err(ConnectError::Quit);
+    };
+
+    // Agree on the session parameters with the display
+    let mut features =
+        FEATURE_AUDIO | FEATURE_PORT_FORWARD | FEATURE_CLIPBOARD | FEATURE_FILE_TRANSFER;
+    if gamepad::SUPPORTED {
+        features |= FEATURE_GAMEPAD;
+    }
+    let ours = SessionHello {
+        version: PROTOCOL_VERSION,
+        codecs: vec![Codec::H264],
+        video: Some(VideoFormat {
+            width: config.region.width,
+            height: config.region.height,
+            frame_rate: config.frame_rate,
+        }),
+        audio: AudioFormat {
+            sample_rate: 48000,
+            channels: 2,
+        },
+        features,
+    };
+    let negotiated =
+        handshake::exchange(&chan, &ours).and_then(|theirs| handshake::negotiate(&ours, &theirs));
+    let negotiated = match negotiated {
+        Ok(n) => n,
+        Err(HandshakeError::Io(e)) => {
+            chan.close();
+            return Err(e.into());
+        }
+        Err(e @ HandshakeError::Decode(_)) => {
+            chan.close();
+            return Err(ConnectError::Retry(e.to_string()));
+        }
+        Err(e) => {
+            error!("{}", e);
+            chan.close();
+            return Err(ConnectError::Quit);
+        }
+    };
+
+    Ok(Connection {
+        sock,
+        chan,
+        secure,
+        peer,
+        access,
+        negotiated,
+    })
+}
 
-        sleep_until(loop_start + FRAME_DURATION);
-        main_fli.measure("sleep");
-        ui.lock()
-            .unwrap()
-            .add_frame_latency_info("main_loop", main_fli);
+// Sleeps for the duration, returning false early if the ui was closed
+fn wait(duration: Duration, ui_thread: &JoinHandle<()>) -> bool {
+    let until = Instant::now() + duration;
+    while Instant::now() < until {
+        if ui_thread.is_finished() {
+            return false;
+        }
+        sleep(Duration::from_millis(100));
     }
+    true
 }
@@ -0,0 +1,58 @@
+use common::msgs::{MouseButton, ScrollDelta};
+use enigo::{Axis, Enigo, Mouse};
+use log::info;
+
+// How far a touchpad has to scroll to move one wheel notch
+const PIXELS_PER_LINE: f64 = 20.;
+// Enigo scrolls one notch at a time on x11, so don't let one event scroll forever
+const MAX_LINES_PER_EVENT: f64 = 50.;
+
+pub fn button(button: MouseButton) -> Option<enigo::Button> {
+    match button {
+        MouseButton::Left => Some(enigo::Button::Left),
+        MouseButton::Middle => Some(enigo::Button::Middle),
+        MouseButton::Right => Some(enigo::Button::Right),
+        MouseButton::Back => Some(enigo::Button::Back),
+        MouseButton::Forward => Some(enigo::Button::Forward),
+        MouseButton::Other(_) => None,
+    }
+}
+
+// Enigo can only scroll by whole notches, so the remainder of smooth scrolling
+// is carried over to the next event
+#[derive(Default)]
+pub struct Scroller {
+    x: f64,
+    y: f64,
+}
+
+impl Scroller {
+    pub fn scroll(&mut self, enigo: &mut Enigo, delta: ScrollDelta) {
+        let (x, y) = match delta {
+            ScrollDelta::Lines { x, y } => (x as f64, y as f64),
+            ScrollDelta::Pixels { x, y } => (x / PIXELS_PER_LINE, y / PIXELS_PER_LINE),
+        };
+        // A NaN would stick in the remainder and stop that axis scrolling
+        if !x.is_finite() || !y.is_finite() {
+            return;
+        }
+        self.x = (self.x + x).clamp(-MAX_LINES_PER_EVENT, MAX_LINES_PER_EVENT);
+        self.y = (self.y + y).clamp(-MAX_LINES_PER_EVENT, MAX_LINES_PER_EVENT);
+
+        // Enigo scrolls down and right for positive lengths, the opposite of winit
+        for (axis, acc) in [
+            (Axis::Horizontal, &mut self.x),
+            (Axis::Vertical, &mut self.y),
+        ] {
+            let lines = acc.trunc();
+            if lines == 0. {
+                continue;
+            }
+            *acc -= lines;
+
+            if let Err(e) = enigo.scroll(-lines as i32, axis) {
+                info!("failed to scroll: {}", e);
+            }
+        }
+    }
+}
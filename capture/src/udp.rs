@@ -1,48 +1,73 @@
 use std::{
     collections::VecDeque,
     net::UdpSocket,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use common::msgs::RTMsg;
+use common::{
+    clock,
+    fec::FecEncoder,
+    msgs::{AudioHeader, FrameHeader, RTMsg, UDP_HEARTBEAT_INTERVAL},
+    secure::SecureChannel,
+};
 
 const UDP_HISTORY: Duration = Duration::from_millis(1000);
 
 pub struct UdpStream {
     sock: UdpSocket,
+    secure: Arc<SecureChannel>,
     history: VecDeque<(RTMsg, Instant)>,
 
     cur_seq: i64,
+    fec: Option<FecEncoder>,
+    last_sent: Instant,
 }
 
 impl UdpStream {
-    pub fn new(sock: UdpSocket) -> Self {
+    // A parity packet is sent after every fec_group_size packets, or never if it's 0
+    pub fn new(sock: UdpSocket, secure: Arc<SecureChannel>, fec_group_size: usize) -> Self {
         Self {
             sock,
+            secure,
             history: VecDeque::new(),
             cur_seq: 0,
+            fec: (fec_group_size > 0).then(|| FecEncoder::new(fec_group_size)),
+            last_sent: Instant::now(),
         }
     }
 
-    pub fn send_packet(&mut self, data: Vec<u8>, is_audio: bool) {
+    pub fn send_packet(
+        &mut self,
+        data: Vec<u8>,
+        frame: Option<FrameHeader>,
+        audio: Option<AudioHeader>,
+    ) {
         // Create msg struct and increment seq numbers
         let msg = RTMsg {
             seq: self.cur_seq,
-            is_audio,
+            is_audio: audio.is_some(),
+            sent_us: clock::now_us(),
+            frame,
+            audio,
+            parity: None,
             data,
         };
         self.cur_seq += 1;
 
-        // Serialize and send
-        let buf = rmp_serde::to_vec(&msg).unwrap();
-        self.sock.send(&buf).unwrap();
+        self.send(&msg);
+        self.last_sent = Instant::now();
+
+        if let Some(parity) = self.fec.as_mut().and_then(|f| f.add(&msg)) {
+            self.send(&parity);
+        }
 
         // Store in history
         self.history.push_back((msg, Instant::now()));
 
         // Remove old packets
         while let Some((_, i)) = self.history.front() {
-            if i.duration_since(Instant::now()) < UDP_HISTORY {
+            if i.elapsed() < UDP_HISTORY {
                 break;
             }
 
@@ -50,13 +75,36 @@ impl UdpStream {
         }
     }
 
+    // Sends parity for the packets since the last one, so a frame's losses can be repaired
+    // without waiting for the next frame to fill the group
+    pub fn flush_fec(&mut self) {
+        if let Some(parity) = self.fec.as_mut().and_then(|f| f.flush()) {
+            self.send(&parity);
+        }
+    }
+
+    // Sends a heartbeat if nothing else has been sent for a while, so the display
+    // knows the capture is still there
+    pub fn keep_alive(&mut self) {
+        if self.last_sent.elapsed() >= UDP_HEARTBEAT_INTERVAL {
+            self.send_packet(vec![], None, None);
+        }
+    }
+
+    // Serializes, encrypts and sends a message
+    fn send(&self, msg: &RTMsg) {
+        let buf = rmp_serde::to_vec(msg).unwrap();
+        // Failing is the same as the packet being lost, and losing the display
+        // altogether is noticed by the heartbeats
+        let _ = self.sock.send(&self.secure.seal_udp(&buf));
+    }
+
     pub fn process_nack(&mut self, seq: i64) {
         // Find the old message in the history
         match self.history.iter().find(|(m, _)| m.seq == seq) {
             Some((m, _)) => {
-                // Retransmit the message
-                let buf = rmp_serde::to_vec(m).unwrap();
-                self.sock.send(&buf).unwrap();
+                // Retransmit the message, under a new nonce
+                self.send(m);
             }
             None => {
                 // Can't find it, don't bother
@@ -65,3 +113,10 @@ impl UdpStream {
         }
     }
 }
+
+// Checks whether the annex b stream contains an idr slice
+pub fn is_keyframe(nalus: &[u8]) -> bool {
+    nalus
+        .windows(4)
+        .any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1f == 5)
+}
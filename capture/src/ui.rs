@@ -1,22 +1,29 @@
 use std::{
     collections::{HashMap, VecDeque},
     io::{self, stdout},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
     time::Instant,
 };
 
+use common::{msgs::LatencyReport, secure::PeerIdentity};
 use log::Level;
 
+use crate::approval::Approval;
+
 use ratatui::{
     crossterm::{
         event::{self, Event, KeyCode},
         terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
         ExecutableCommand,
     },
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     prelude::CrosstermBackend,
-    widgets::{Block, Paragraph},
+    widgets::{Block, Clear, Paragraph},
     Frame, Terminal,
 };
 
@@ -50,9 +57,15 @@ impl FrameLatencyInfo {
 pub struct UI {
     infos: HashMap<String, VecDeque<FrameLatencyInfo>>,
     log: String,
+    bitrate_kbps: u32,
+    latency: Option<LatencyReport>,
+    // A display waiting to be let in, and where to send the answer
+    approval: Option<(PeerIdentity, Sender<Approval>)>,
+    // Whether the clipboard is shared with the display, toggled with c
+    clipboard: Arc<AtomicBool>,
 }
 
-pub fn start_ui() -> (Arc<Mutex<UI>>, JoinHandle<()>) {
+pub fn start_ui(clipboard: Arc<AtomicBool>) -> (Arc<Mutex<UI>>, JoinHandle<()>) {
     enable_raw_mode().unwrap();
     stdout().execute(EnterAlternateScreen).unwrap();
 
@@ -63,6 +76,10 @@ pub fn start_ui() -> (Arc<Mutex<UI>>, JoinHandle<()>) {
             ("main_loop".into(), VecDeque::new()),
         ]),
         log: String::new(),
+        bitrate_kbps: 0,
+        latency: None,
+        approval: None,
+        clipboard,
     }));
 
     let ui = u.clone();
@@ -97,11 +114,43 @@ impl UI {
         }
     }
 
+    pub fn set_bitrate(&mut self, bitrate_kbps: u32) {
+        self.bitrate_kbps = bitrate_kbps;
+    }
+
+    // Sets the latest glass to glass latency measured by the display
+    pub fn set_latency(&mut self, latency: LatencyReport) {
+        self.latency = Some(latency);
+    }
+
+    // Shows a prompt asking whether to let the display in
+    pub fn ask_approval(&mut self, peer: PeerIdentity, answer: Sender<Approval>) {
+        self.approval = Some((peer, answer));
+    }
+
     fn handle_events(&mut self) -> io::Result<bool> {
         if let Event::Key(key) = event::read()? {
-            if key.kind == event::KeyEventKind::Press && key.code == KeyCode::Char('q') {
+            if key.kind != event::KeyEventKind::Press {
+                return Ok(false);
+            }
+            if key.code == KeyCode::Char('q') {
                 return Ok(true);
             }
+
+            if self.approval.is_some() {
+                let approval = match key.code {
+                    KeyCode::Char('v') => Approval::ViewOnly,
+                    KeyCode::Char('a') => Approval::AllowInput,
+                    KeyCode::Char('t') => Approval::Trust,
+                    KeyCode::Char('r') => Approval::Reject,
+                    _ => return Ok(false),
+                };
+
+                let (_, answer) = self.approval.take().unwrap();
+                let _ = answer.send(approval);
+            } else if key.code == KeyCode::Char('c') {
+                self.clipboard.fetch_xor(true, Ordering::Relaxed);
+            }
         }
 
         Ok(false)
@@ -156,15 +205,61 @@ impl UI {
             i += 1;
         }
 
+        let bottom = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(vec![Constraint::Percentage(75), Constraint::Percentage(25)])
+            .split(layout[2]);
+
         frame.render_widget(
             Paragraph::new(self.log.clone())
-                .block(Block::bordered().title("Log"))
+                .block(Block::bordered().title(format!(
+                    "Log (video bitrate {} kbit/s, clipboard {}, [c] to toggle)",
+                    self.bitrate_kbps,
+                    match self.clipboard.load(Ordering::Relaxed) {
+                        true => "shared",
+                        false => "not shared",
+                    }
+                )))
                 .scroll((
-                    (self.log.split('\n').count() as i32 - layout[2].height as i32).max(0) as u16,
+                    (self.log.split('\n').count() as i32 - bottom[0].height as i32).max(0) as u16,
                     0,
                 )),
-            layout[2],
+            bottom[0],
         );
+
+        frame.render_widget(self.latency_widget(), bottom[1]);
+
+        if let Some((peer, _)) = &self.approval {
+            let area = frame.area();
+            let popup = Rect {
+                x: area.width / 6,
+                y: area.height.saturating_sub(8) / 2,
+                width: area.width * 2 / 3,
+                height: 8.min(area.height),
+            };
+
+            let text = format!(
+                "Display {:?} wants to connect\nFingerprint {}\n\n[a] allow input  [v] view only  [t] always allow this display  [r] reject",
+                peer.name, peer.fingerprint
+            );
+            frame.render_widget(Clear, popup);
+            frame.render_widget(
+                Paragraph::new(text).block(Block::bordered().title("Approve display")),
+                popup,
+            );
+        }
+    }
+
+    fn latency_widget(&self) -> Paragraph<'static> {
+        let text = match self.latency {
+            Some(l) => format!(
+                "capture {} us\nencode {} us\nnetwork {} us\ndecode {} us\npresent {} us\ntotal {} us\n\nrtt {} us",
+                l.capture_us, l.encode_us, l.network_us, l.decode_us, l.present_us, l.total_us, l.rtt_us
+            ),
+            None => "waiting for display".into(),
+        };
+
+        Paragraph::new(text).block(Block::bordered().title("Glass to glass"))
     }
 
     fn framelatency_widget(&self, l: &FrameLatencyInfo, title: String) -> Paragraph<'static> {
@@ -1,157 +1,662 @@
 use std::{
     collections::{HashMap, VecDeque},
-    io::{Read, Write},
-    net::TcpStream,
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
     sync::{
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex,
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Condvar, Mutex,
     },
     thread,
+    time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
 
+use crate::secure::{SecureChannel, SecureReader, SecureWriter};
+
+// How much can be sent on a subchannel before its reader has to catch up. This is
+// also as much as is buffered for a subchannel that hasn't been created yet
+const WINDOW: u64 = 1 << 20;
+
+// How many subchannels the other end can send to before they're created here
+const MAX_PENDING_SUBCHANS: usize = 256;
+
+// Readers give back credit in batches of this much, so the writer isn't woken up
+// for every read
+const CREDIT_BATCH: usize = 64 << 10;
+
+// Writes are split up so a big one can't hold up interactive subchannels for long
+const MAX_FRAME: usize = 16 << 10;
+
+// Sent when nothing else has been for a while, so a connection that silently went
+// away is noticed
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum ChannelId {
     // Inter-client protocol
     Initial,
     Keys,
+    Control,
     PortForwardControl,
     PortForwardSub(u64),
+    Gamepad,
+    Clipboard,
+    FileTransfer,
 
     // Internal client IPC protocol
     IPC,
 }
 
+// Lower is sent first
+#[derive(Clone, Copy)]
+enum Priority {
+    Interactive = 0,
+    Normal = 1,
+    Bulk = 2,
+}
+
+impl ChannelId {
+    fn priority(&self) -> Priority {
+        match self {
+            ChannelId::Initial
+            | ChannelId::Keys
+            | ChannelId::Control
+            | ChannelId::PortForwardControl
+            | ChannelId::Gamepad => Priority::Interactive,
+            ChannelId::Clipboard | ChannelId::IPC => Priority::Normal,
+            ChannelId::PortForwardSub(_) | ChannelId::FileTransfer => Priority::Bulk,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
-struct ChanPacket {
-    chan_id: ChannelId,
-    #[serde(with = "serde_bytes")]
-    data: Vec<u8>,
+enum Frame {
+    Data {
+        chan_id: ChannelId,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    // Nothing more will be written to this subchannel, its reader sees EOF
+    Close {
+        chan_id: ChannelId,
+    },
+    // The reader of a subchannel has taken this many more bytes, so they can be sent
+    Credit {
+        chan_id: ChannelId,
+        bytes: u64,
+    },
+    Heartbeat,
 }
 
-pub struct TcpChan {
-    subchans: Arc<Mutex<HashMap<ChannelId, Sender<ChanPacket>>>>,
-    subchan_tx: Sender<ChanPacket>, // used for creating more subchannels
+impl Frame {
+    fn priority(&self) -> Priority {
+        match self {
+            Frame::Data { chan_id, .. } | Frame::Close { chan_id } => chan_id.priority(),
+            Frame::Credit { .. } | Frame::Heartbeat => Priority::Interactive,
+        }
+    }
+}
+
+enum Outgoing {
+    Frame(Frame),
+    // Sent by TcpChan::close() after everything that should still go out
+    Shutdown,
+}
+
+// What a subchannel reader is sent, None once the other end has closed it
+type Received = Option<Vec<u8>>;
+
+// Arrived before its subchannel was created
+#[derive(Default)]
+struct Pending {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+// Whether the connection under a TcpChan can still be used. It only ever leaves Open once
+#[derive(Clone, PartialEq, Debug)]
+pub enum ChanState {
+    Open,
+    // Shut down with close()
+    Closed,
+    // The connection broke or the other end went away
+    Failed(io::ErrorKind, String),
+}
+
+impl ChanState {
+    // What reading or writing a subchannel fails with once the connection is gone
+    fn error(&self) -> io::Error {
+        match self {
+            ChanState::Open => io::Error::new(io::ErrorKind::BrokenPipe, "subchannel closed"),
+            ChanState::Closed => io::Error::new(io::ErrorKind::NotConnected, "channel was closed"),
+            ChanState::Failed(kind, reason) => io::Error::new(*kind, reason.clone()),
+        }
+    }
+}
+
+impl fmt::Display for ChanState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChanState::Open => write!(f, "open"),
+            ChanState::Closed => write!(f, "closed"),
+            ChanState::Failed(_, reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+type Observer = Box<dyn FnOnce(&ChanState) + Send>;
+
+struct Status {
+    state: ChanState,
+    // Called when the state leaves Open
+    observers: Vec<Observer>,
+}
+
+// Shared by the TcpChan, its threads and its subchannels
+struct Shared {
+    status: Mutex<Status>,
+    // Removed once the other end closes them
+    subchans: Mutex<HashMap<ChannelId, Sender<Received>>>,
+    pending_subchans: Mutex<HashMap<ChannelId, Pending>>,
+    // Received on each subchannel but not yet given back as credit, which the other
+    // end keeps within WINDOW
+    unread: Mutex<HashMap<ChannelId, u64>>,
+    // How much each open subchannel writer may still send
+    credits: Mutex<HashMap<ChannelId, u64>>,
+    // Notified when credit comes back or the connection ends
+    credit_ready: Condvar,
+}
+
+impl Shared {
+    fn state(&self) -> ChanState {
+        self.status.lock().unwrap().state.clone()
+    }
+
+    // Leaves Open for good, waking up every subchannel reader. Later calls do nothing
+    fn end(&self, state: ChanState) {
+        let observers = {
+            let mut status = self.status.lock().unwrap();
+            if status.state != ChanState::Open {
+                return;
+            }
+            status.state = state.clone();
+            std::mem::take(&mut status.observers)
+        };
+
+        // Readers still get what already arrived before the error
+        self.subchans.lock().unwrap().clear();
+        {
+            let _credits = self.credits.lock().unwrap();
+            self.credit_ready.notify_all();
+        }
+        for f in observers {
+            f(&state);
+        }
+    }
+
+    // Counts bytes received on a subchannel, failing if the other end sent more than
+    // it had credit for
+    fn receive(&self, chan_id: ChannelId, bytes: u64) -> io::Result<()> {
+        let mut unread = self.unread.lock().unwrap();
+        let n = unread.entry(chan_id).or_default();
+        *n += bytes;
+        if *n > WINDOW {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the other end sent more than a subchannel's window",
+            ));
+        }
+        Ok(())
+    }
+
+    // Counts bytes which were read or thrown away, so the other end can send as much again
+    fn release(&self, chan_id: ChannelId, bytes: u64) {
+        let mut unread = self.unread.lock().unwrap();
+        if let Some(n) = unread.get_mut(&chan_id) {
+            *n = n.saturating_sub(bytes);
+            if *n == 0 {
+                unread.remove(&chan_id);
+            }
+        }
+    }
 
-    pending_subchans: Arc<Mutex<HashMap<ChannelId, VecDeque<u8>>>>,
+    // Keeps what arrived for a subchannel until it's created here
+    fn pend(&self, chan_id: ChannelId, f: impl FnOnce(&mut Pending)) -> io::Result<()> {
+        let mut pending = self.pending_subchans.lock().unwrap();
+        if !pending.contains_key(&chan_id) && pending.len() >= MAX_PENDING_SUBCHANS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the other end sent to too many subchannels that weren't opened",
+            ));
+        }
+        f(pending.entry(chan_id).or_default());
+        Ok(())
+    }
+}
+
+// Clones share the same connection
+#[derive(Clone)]
+pub struct TcpChan {
+    shared: Arc<Shared>,
+    subchan_tx: Sender<Outgoing>, // used for creating more subchannels
 }
 
 impl TcpChan {
+    // Unencrypted, only for connections that never leave this machine
     pub fn new(tcp: TcpStream) -> Self {
+        Self::start(tcp.try_clone().unwrap(), tcp.try_clone().unwrap(), tcp)
+    }
+
+    // Encrypts and authenticates everything sent between the clients
+    pub fn new_secure(tcp: TcpStream, secure: Arc<SecureChannel>) -> Self {
+        Self::start(
+            SecureReader::new(tcp.try_clone().unwrap(), secure.clone()),
+            SecureWriter::new(tcp.try_clone().unwrap(), secure),
+            tcp,
+        )
+    }
+
+    fn start(
+        r: impl Read + Send + 'static,
+        w: impl Write + Send + 'static,
+        tcp: TcpStream,
+    ) -> Self {
+        // Applies to the reader's clone of the socket too
+        tcp.set_read_timeout(Some(HEARTBEAT_TIMEOUT)).unwrap();
+
         let (subchan_tx, subchan_rx) = mpsc::channel();
-        let sbchc = Arc::new(Mutex::new(HashMap::new()));
-        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let shared = Arc::new(Shared {
+            status: Mutex::new(Status {
+                state: ChanState::Open,
+                observers: Vec::new(),
+            }),
+            subchans: Mutex::new(HashMap::new()),
+            pending_subchans: Mutex::new(HashMap::new()),
+            unread: Mutex::new(HashMap::new()),
+            credits: Mutex::new(HashMap::new()),
+            credit_ready: Condvar::new(),
+        });
 
-        let r_ts = tcp.try_clone().unwrap();
-        let r_sbchc = sbchc.clone();
-        let r_pending = pending.clone();
+        let r_shared = shared.clone();
+        let r_tx = subchan_tx.clone();
         thread::spawn(move || {
-            TcpChan::_read(r_ts, r_sbchc, r_pending);
+            let e = TcpChan::_read(r, &r_shared, &r_tx);
+            r_shared.end(ChanState::Failed(e.kind(), e.to_string()));
+            // Stops the writer too
+            let _ = r_tx.send(Outgoing::Shutdown);
         });
 
+        let w_shared = shared.clone();
         thread::spawn(move || {
-            TcpChan::_write(tcp, subchan_rx);
+            match TcpChan::_write(w, subchan_rx) {
+                Ok(()) => w_shared.end(ChanState::Closed),
+                Err(e) => w_shared.end(ChanState::Failed(e.kind(), e.to_string())),
+            }
+
+            // Stops the reader too
+            let _ = tcp.shutdown(Shutdown::Both);
         });
 
-        Self {
-            subchans: sbchc,
-            subchan_tx,
-            pending_subchans: pending,
-        }
+        Self { shared, subchan_tx }
     }
 
+    // Opens a subchannel, or opens it again once both ends have closed it
     pub fn create_subchan(&self, id: ChannelId) -> (SubChanWriter, SubChanReader) {
         let (tx, rx) = mpsc::channel();
-        self.subchans.lock().unwrap().insert(id, tx);
+        let pending = {
+            let status = self.shared.status.lock().unwrap();
+            let mut subchans = self.shared.subchans.lock().unwrap();
+
+            // Check for a pending subchan, and use its buf
+            let pending = self
+                .shared
+                .pending_subchans
+                .lock()
+                .unwrap()
+                .remove(&id)
+                .unwrap_or_default();
 
-        // Check for a pending subchan, and use its buf
-        let mut guard = self.pending_subchans.lock().unwrap();
-        let r_buf = match guard.get(&id) {
-            Some(_) => guard.remove(&id).unwrap(),
-            None => VecDeque::new(),
+            // Readers of a subchannel made after the connection ended get an error
+            // once they've read what's pending
+            if status.state == ChanState::Open && !pending.closed {
+                subchans.insert(id, tx);
+            }
+            pending
         };
+        self.shared.credits.lock().unwrap().insert(id, WINDOW);
 
         (
             SubChanWriter {
+                inner: Arc::new(WriterInner {
+                    chan_id: id,
+                    tx: self.subchan_tx.clone(),
+                    shared: self.shared.clone(),
+                    closed: Mutex::new(false),
+                }),
+            },
+            SubChanReader {
                 chan_id: id,
+                rx,
+                r_buf: pending.buf,
+                eof: pending.closed,
+                taken: 0,
                 tx: self.subchan_tx.clone(),
+                shared: self.shared.clone(),
             },
-            SubChanReader { rx, r_buf },
         )
     }
 
-    fn _read(
-        mut ts: TcpStream,
-        subchans: Arc<Mutex<HashMap<ChannelId, Sender<ChanPacket>>>>,
-        pending_subchans: Arc<Mutex<HashMap<ChannelId, VecDeque<u8>>>>,
-    ) {
+    pub fn state(&self) -> ChanState {
+        self.shared.state()
+    }
+
+    // Calls f once the connection is closed or fails, or straight away if it already has
+    pub fn on_close(&self, f: impl FnOnce(&ChanState) + Send + 'static) {
+        let mut status = self.shared.status.lock().unwrap();
+        if status.state == ChanState::Open {
+            status.observers.push(Box::new(f));
+            return;
+        }
+
+        let state = status.state.clone();
+        drop(status);
+        f(&state);
+    }
+
+    // Sends what's already been written, then shuts down the connection. Subchannels
+    // fail from now on
+    pub fn close(&self) {
+        self.shared.end(ChanState::Closed);
+        let _ = self.subchan_tx.send(Outgoing::Shutdown);
+    }
+
+    // Like close(), but for a problem noticed outside the connection, e.g. udp no
+    // longer getting through
+    pub fn fail(&self, e: io::Error) {
+        self.shared.end(ChanState::Failed(e.kind(), e.to_string()));
+        let _ = self.subchan_tx.send(Outgoing::Shutdown);
+    }
+
+    // Returns why the connection can't be read from any more
+    fn _read(mut ts: impl Read, shared: &Shared, tx: &Sender<Outgoing>) -> io::Error {
         loop {
-            let p: ChanPacket = rmp_serde::from_read(std::io::Read::by_ref(&mut ts)).unwrap();
-            let sub_guard = subchans.lock().unwrap();
-            if sub_guard.get(&p.chan_id).is_none() {
-                // Write to the pending subchan for it
-                let mut pending_guard = pending_subchans.lock().unwrap();
-                if pending_guard.get(&p.chan_id).is_none() {
-                    // Create a pending subchan if it doesn't exist
-                    pending_guard.insert(p.chan_id, VecDeque::new());
-                }
+            let frame: Frame = match rmp_serde::from_read(std::io::Read::by_ref(&mut ts)) {
+                Ok(frame) => frame,
+                Err(e) => return read_error(e),
+            };
 
-                pending_guard.get_mut(&p.chan_id).unwrap().extend(p.data);
-            } else {
-                sub_guard[&p.chan_id].send(p).unwrap();
+            let mut sub_guard = shared.subchans.lock().unwrap();
+            match frame {
+                Frame::Data { chan_id, data } => {
+                    let bytes = data.len() as u64;
+                    if let Err(e) = shared.receive(chan_id, bytes) {
+                        return e;
+                    }
+
+                    match sub_guard.get(&chan_id) {
+                        Some(r_tx) => {
+                            // Nobody is reading it any more, so let the other end carry on
+                            if r_tx.send(Some(data)).is_err() {
+                                shared.release(chan_id, bytes);
+                                let _ = tx.send(Outgoing::Frame(Frame::Credit { chan_id, bytes }));
+                            }
+                        }
+                        // Write to the pending subchan for it, creating it if it doesn't exist
+                        None => {
+                            if let Err(e) = shared.pend(chan_id, |p| p.buf.extend(data)) {
+                                return e;
+                            }
+                        }
+                    }
+                }
+                Frame::Close { chan_id } => match sub_guard.remove(&chan_id) {
+                    Some(r_tx) => {
+                        let _ = r_tx.send(None);
+                    }
+                    None => {
+                        if let Err(e) = shared.pend(chan_id, |p| p.closed = true) {
+                            return e;
+                        }
+                    }
+                },
+                Frame::Credit { chan_id, bytes } => {
+                    // Ignored once the writer has closed it
+                    drop(sub_guard);
+                    if let Some(credit) = shared.credits.lock().unwrap().get_mut(&chan_id) {
+                        *credit += bytes;
+                        shared.credit_ready.notify_all();
+                    }
+                }
+                Frame::Heartbeat => {}
             }
         }
     }
 
-    fn _write(mut ts: TcpStream, subchan_rx: Receiver<ChanPacket>) {
+    // Sends frames of higher priority subchannels first, keeping the order within each.
+    // Returns once close() is called and everything before it has been sent
+    fn _write(mut ts: impl Write, subchan_rx: Receiver<Outgoing>) -> io::Result<()> {
+        let mut queues: [VecDeque<Frame>; 3] = Default::default();
+        let mut shutdown = false;
         loop {
-            let p = subchan_rx.recv().unwrap();
-            let b = rmp_serde::to_vec(&p).unwrap();
-            ts.write_all(&b).unwrap();
+            // Wait for something to send, then take everything else that's queued up
+            let mut next = match queues.iter().all(|q| q.is_empty()) {
+                true if shutdown => break,
+                true => match subchan_rx.recv_timeout(HEARTBEAT_INTERVAL) {
+                    Ok(outgoing) => Ok(outgoing),
+                    Err(RecvTimeoutError::Timeout) => Ok(Outgoing::Frame(Frame::Heartbeat)),
+                    Err(RecvTimeoutError::Disconnected) => Err(TryRecvError::Disconnected),
+                },
+                false => subchan_rx.try_recv(),
+            };
+            loop {
+                match next {
+                    Ok(Outgoing::Frame(frame)) => {
+                        queues[frame.priority() as usize].push_back(frame)
+                    }
+                    Ok(Outgoing::Shutdown) | Err(TryRecvError::Disconnected) => shutdown = true,
+                    Err(TryRecvError::Empty) => break,
+                }
+                if shutdown {
+                    break;
+                }
+                next = subchan_rx.try_recv();
+            }
+
+            if let Some(frame) = queues.iter_mut().find_map(|q| q.pop_front()) {
+                let b = rmp_serde::to_vec(&frame).unwrap();
+                ts.write_all(&b)?;
+            }
+        }
+        ts.flush()
+    }
+}
+
+fn read_error(e: rmp_serde::decode::Error) -> io::Error {
+    match e {
+        rmp_serde::decode::Error::InvalidMarkerRead(e)
+            if e.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            io::Error::new(e.kind(), "connection closed by the other end")
+        }
+        // The read timed out
+        rmp_serde::decode::Error::InvalidMarkerRead(e)
+        | rmp_serde::decode::Error::InvalidDataRead(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "nothing heard from the other end for {:?}",
+                    HEARTBEAT_TIMEOUT
+                ),
+            )
         }
+        rmp_serde::decode::Error::InvalidMarkerRead(e)
+        | rmp_serde::decode::Error::InvalidDataRead(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
     }
 }
 
 pub struct SubChanReader {
-    rx: Receiver<ChanPacket>,
+    chan_id: ChannelId,
+    rx: Receiver<Received>,
     r_buf: VecDeque<u8>,
+    // The other end closed it, so reads return 0 once r_buf is empty
+    eof: bool,
+    // Read since credit was last given back
+    taken: usize,
+    tx: Sender<Outgoing>,
+    shared: Arc<Shared>,
+}
+
+impl SubChanReader {
+    fn give_credit(&mut self, bytes: usize) {
+        self.shared.release(self.chan_id, bytes as u64);
+        let _ = self.tx.send(Outgoing::Frame(Frame::Credit {
+            chan_id: self.chan_id,
+            bytes: bytes as u64,
+        }));
+    }
 }
 
 impl Read for SubChanReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         // Recv a packet if the buf is empty
-        if self.r_buf.len() == 0 {
-            let packet = self.rx.recv();
-            match packet {
-                Ok(packet) => {
-                    self.r_buf.extend(packet.data);
-                }
-                Err(e) => {
-                    return Err(std::io::Error::other(e));
-                }
+        while self.r_buf.is_empty() && !self.eof {
+            match self.rx.recv() {
+                Ok(Some(data)) => self.r_buf.extend(data),
+                Ok(None) => self.eof = true,
+                Err(_) => return Err(self.shared.state().error()),
             }
         }
 
         // Read from the buf
-        self.r_buf.read(buf)
+        let n = self.r_buf.read(buf)?;
+        self.taken += n;
+        if self.taken >= CREDIT_BATCH {
+            self.give_credit(self.taken);
+            self.taken = 0;
+        }
+        Ok(n)
     }
 }
 
+impl Drop for SubChanReader {
+    // Everything that won't be read now is given back, so the other end isn't left
+    // waiting for credit
+    fn drop(&mut self) {
+        let mut unread = self.taken + self.r_buf.len();
+        for data in self.rx.try_iter().flatten() {
+            unread += data.len();
+        }
+
+        // Once the other end has closed it, nobody is waiting for the credit
+        match self.eof {
+            true => self.shared.release(self.chan_id, unread as u64),
+            false if unread > 0 => self.give_credit(unread),
+            false => {}
+        }
+    }
+}
+
+// Clones write to the same subchannel. It's closed when the last one is dropped,
+// if close() wasn't called first
+#[derive(Clone)]
 pub struct SubChanWriter {
+    inner: Arc<WriterInner>,
+}
+
+struct WriterInner {
     chan_id: ChannelId,
-    tx: Sender<ChanPacket>,
+    tx: Sender<Outgoing>,
+    shared: Arc<Shared>,
+    // Held while sending, so nothing is sent after the close frame
+    closed: Mutex<bool>,
+}
+
+impl WriterInner {
+    fn close(&self) {
+        let mut closed = self.closed.lock().unwrap();
+        if !*closed {
+            *closed = true;
+            self.shared.credits.lock().unwrap().remove(&self.chan_id);
+            let _ = self.tx.send(Outgoing::Frame(Frame::Close {
+                chan_id: self.chan_id,
+            }));
+        }
+    }
+
+    // Waits until up to len bytes can be sent, and takes the credit for them
+    fn take_credit(&self, len: usize) -> io::Result<usize> {
+        let mut credits = self.shared.credits.lock().unwrap();
+        loop {
+            let state = self.shared.state();
+            if state != ChanState::Open {
+                return Err(state.error());
+            }
+
+            let credit = match credits.get_mut(&self.chan_id) {
+                Some(credit) => credit,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "subchannel was closed",
+                    ))
+                }
+            };
+            if *credit > 0 {
+                let n = len.min(MAX_FRAME).min(*credit as usize);
+                *credit -= n as u64;
+                return Ok(n);
+            }
+            credits = self.shared.credit_ready.wait(credits).unwrap();
+        }
+    }
+}
+
+impl Drop for WriterInner {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl SubChanWriter {
+    // Tells the other end there's nothing more to read, like shutting down the
+    // write half of a tcp socket. Reading from this subchannel still works
+    pub fn close(&self) {
+        self.inner.close();
+    }
 }
 
 impl Write for SubChanWriter {
+    // Blocks while the other end is too far behind, and may write less than buf
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.inner.take_credit(buf.len())?;
+
+        let closed = self.inner.closed.lock().unwrap();
+        if *closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "subchannel was closed",
+            ));
+        }
+
         // Serialize to packet and send
-        match self.tx.send(ChanPacket {
-            chan_id: self.chan_id,
-            data: buf.to_vec(),
-        }) {
-            Ok(_) => Ok(buf.len()),
-            Err(e) => Err(std::io::Error::other(e)),
+        match self.inner.tx.send(Outgoing::Frame(Frame::Data {
+            chan_id: self.inner.chan_id,
+            data: buf[..n].to_vec(),
+        })) {
+            Ok(_) => Ok(n),
+            Err(_) => Err(self.inner.shared.state().error()),
         }
     }
 
@@ -160,3 +665,181 @@ impl Write for SubChanWriter {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::atomic::{AtomicU64, Ordering},
+        time::Instant,
+    };
+
+    use super::*;
+
+    // Both ends of a connection over loopback
+    fn pair() -> (TcpChan, TcpChan) {
+        let (a, b) = socket_pair();
+        (TcpChan::new(a), TcpChan::new(b))
+    }
+
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+        (a, b)
+    }
+
+    // Polls until f is true, failing the test if it takes too long
+    fn wait_for(f: impl Fn() -> bool) {
+        let started = Instant::now();
+        while !f() {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn eof_after_remote_close() {
+        let (a, b) = pair();
+        let (mut w, _) = a.create_subchan(ChannelId::Control);
+        let (_, mut r) = b.create_subchan(ChannelId::Control);
+
+        w.write_all(b"hello").unwrap();
+        w.close();
+        assert!(w.write(b"more").is_err());
+
+        let mut got = vec![];
+        r.read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"hello");
+        // Stays at EOF
+        assert_eq!(r.read(&mut [0; 8]).unwrap(), 0);
+    }
+
+    #[test]
+    fn pending_data_delivered_to_late_create() {
+        let (a, b) = pair();
+        let (mut w, _) = a.create_subchan(ChannelId::Gamepad);
+        let (mut marker, _) = a.create_subchan(ChannelId::Keys);
+        let (_, mut marker_r) = b.create_subchan(ChannelId::Keys);
+
+        w.write_all(b"before it was created").unwrap();
+        w.close();
+        // Same priority, so it arrives after the gamepad data and its close
+        marker.write_all(b"x").unwrap();
+        marker_r.read_exact(&mut [0; 1]).unwrap();
+
+        let (_, mut r) = b.create_subchan(ChannelId::Gamepad);
+        let mut got = vec![];
+        r.read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"before it was created");
+    }
+
+    #[test]
+    fn writer_blocks_at_window_until_read() {
+        let (a, b) = pair();
+        let (mut w, _) = a.create_subchan(ChannelId::FileTransfer);
+        let (_, mut r) = b.create_subchan(ChannelId::FileTransfer);
+
+        let total = WINDOW as usize + 100_000;
+        let data: Vec<u8> = (0..total).map(|i| (i % 251) as u8).collect();
+        let written = Arc::new(AtomicU64::new(0));
+        let w_written = written.clone();
+        let w_data = data.clone();
+        let writer = thread::spawn(move || {
+            let mut sent = 0;
+            while sent < w_data.len() {
+                sent += w.write(&w_data[sent..]).unwrap();
+                w_written.store(sent as u64, Ordering::SeqCst);
+            }
+        });
+
+        // Nothing is read, so it stops at the window
+        wait_for(|| written.load(Ordering::SeqCst) == WINDOW);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(written.load(Ordering::SeqCst), WINDOW);
+
+        let mut got = vec![0; total];
+        r.read_exact(&mut got).unwrap();
+        writer.join().unwrap();
+        assert!(got == data);
+    }
+
+    #[test]
+    fn interactive_frames_overtake_queued_bulk() {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..8 {
+            tx.send(Outgoing::Frame(Frame::Data {
+                chan_id: ChannelId::PortForwardSub(1),
+                data: vec![0; MAX_FRAME],
+            }))
+            .unwrap();
+        }
+        tx.send(Outgoing::Frame(Frame::Data {
+            chan_id: ChannelId::Keys,
+            data: b"key".to_vec(),
+        }))
+        .unwrap();
+        tx.send(Outgoing::Shutdown).unwrap();
+
+        let mut out = vec![];
+        TcpChan::_write(&mut out, rx).unwrap();
+
+        let mut r = out.as_slice();
+        let mut order = vec![];
+        while !r.is_empty() {
+            match rmp_serde::from_read::<_, Frame>(&mut r).unwrap() {
+                Frame::Data { chan_id, .. } => order.push(chan_id),
+                _ => panic!("unexpected frame"),
+            }
+        }
+        assert_eq!(order.len(), 9);
+        // Only the bulk frame being waited on goes out before it
+        let keys = order.iter().position(|c| *c == ChannelId::Keys).unwrap();
+        assert!(keys <= 1);
+    }
+
+    #[test]
+    fn overrunning_the_window_fails_the_connection() {
+        let (mut raw, b) = socket_pair();
+        let b = TcpChan::new(b);
+        let (_, _r) = b.create_subchan(ChannelId::FileTransfer);
+
+        for _ in 0..=WINDOW as usize / MAX_FRAME {
+            let frame = Frame::Data {
+                chan_id: ChannelId::FileTransfer,
+                data: vec![0; MAX_FRAME],
+            };
+            if raw.write_all(&rmp_serde::to_vec(&frame).unwrap()).is_err() {
+                break;
+            }
+        }
+
+        wait_for(|| b.state() != ChanState::Open);
+        assert!(matches!(
+            b.state(),
+            ChanState::Failed(io::ErrorKind::InvalidData, _)
+        ));
+    }
+
+    #[test]
+    fn too_many_pending_subchannels_fails_the_connection() {
+        let (mut raw, b) = socket_pair();
+        let b = TcpChan::new(b);
+
+        for id in 0..=MAX_PENDING_SUBCHANS as u64 {
+            let frame = Frame::Data {
+                chan_id: ChannelId::PortForwardSub(id),
+                data: vec![0; 8],
+            };
+            if raw.write_all(&rmp_serde::to_vec(&frame).unwrap()).is_err() {
+                break;
+            }
+        }
+
+        wait_for(|| b.state() != ChanState::Open);
+        assert!(matches!(
+            b.state(),
+            ChanState::Failed(io::ErrorKind::InvalidData, _)
+        ));
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use arboard::{Clipboard, ImageData};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::chan::{SubChanReader, SubChanWriter};
+
+// Bigger contents stay local, so copying something huge doesn't hold up the
+// other channels
+pub const MAX_TEXT_LEN: usize = 1 << 20;
+pub const MAX_IMAGE_LEN: usize = 32 << 20;
+// Messages are read through this limit, so the other client can't make us buffer more.
+// Leaves room for the rest of an image message
+const MAX_MSG_LEN: u64 = MAX_IMAGE_LEN as u64 + 64;
+
+// How often the clipboard is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Where changes can't be watched, images are only read every this many polls
+const IMAGE_POLL_EVERY: u32 = 10;
+
+// Sent both ways over ChannelId::Clipboard when the clipboard changes
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum ClipboardMsg {
+    Text(String),
+    // 8 bit rgba
+    Image {
+        width: u32,
+        height: u32,
+        #[serde(with = "serde_bytes")]
+        rgba: Vec<u8>,
+    },
+}
+
+impl ClipboardMsg {
+    // Also checks an image has as many pixels as it says
+    fn fits(&self) -> bool {
+        match self {
+            ClipboardMsg::Text(text) => text.len() <= MAX_TEXT_LEN,
+            ClipboardMsg::Image {
+                width,
+                height,
+                rgba,
+            } => {
+                rgba.len() <= MAX_IMAGE_LEN
+                    && rgba.len() as u64 == *width as u64 * *height as u64 * 4
+            }
+        }
+    }
+}
+
+// Mirrors the clipboard with the other client whenever enabled is set.
+// Fails if there's no clipboard to use
+pub fn start_clipboard_sync(
+    mut writer: SubChanWriter,
+    mut reader: SubChanReader,
+    enabled: Arc<AtomicBool>,
+) -> Result<(), arboard::Error> {
+    let mut local = Clipboard::new()?;
+    let mut remote = Clipboard::new()?;
+
+    // What's on the clipboard as far as we know, so what the other client sets
+    // isn't sent back to it. Starts with what was there before connecting, so
+    // neither client overwrites the other's straight away
+    let last = Arc::new(Mutex::new(read(&mut local, true)));
+
+    let r_last = last.clone();
+    let r_enabled = enabled.clone();
+    // Set once the connection ends, so the polling stops too
+    let done = Arc::new(AtomicBool::new(false));
+    let r_done = done.clone();
+    thread::spawn(move || loop {
+        // A message over the limit can't be skipped, so that ends the sync too
+        let Ok(msg) = rmp_serde::from_read::<_, ClipboardMsg>((&mut reader).take(MAX_MSG_LEN))
+        else {
+            r_done.store(true, Ordering::Relaxed);
+            return;
+        };
+        if !r_enabled.load(Ordering::Relaxed) || !msg.fits() {
+            continue;
+        }
+
+        *r_last.lock().unwrap() = Some(msg.clone());
+        let result = match msg {
+            ClipboardMsg::Text(text) => remote.set_text(text),
+            ClipboardMsg::Image {
+                width,
+                height,
+                rgba,
+            } => remote.set_image(ImageData {
+                width: width as usize,
+                height: height as usize,
+                bytes: Cow::Owned(rgba),
+            }),
+        };
+        if let Err(e) = result {
+            error!("failed to set the clipboard: {}", e);
+        }
+    });
+
+    let mut changes = Changes::new();
+    let mut polls = 0;
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        if done.load(Ordering::Relaxed) {
+            return;
+        }
+        if !enabled.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let images = match changes.changed() {
+            Some(false) => continue,
+            Some(true) => true,
+            None => {
+                polls += 1;
+                polls % IMAGE_POLL_EVERY == 0
+            }
+        };
+        let Some(msg) = read(&mut local, images) else {
+            continue;
+        };
+        {
+            let mut last = last.lock().unwrap();
+            if last.as_ref() == Some(&msg) {
+                continue;
+            }
+            *last = Some(msg.clone());
+        }
+
+        if msg.fits() && writer.write_all(&rmp_serde::to_vec(&msg).unwrap()).is_err() {
+            return;
+        }
+    });
+
+    Ok(())
+}
+
+// Prefers text, since apps often put an image of copied text on the clipboard too
+fn read(clipboard: &mut Clipboard, images: bool) -> Option<ClipboardMsg> {
+    if let Ok(text) = clipboard.get_text() {
+        return Some(ClipboardMsg::Text(text));
+    }
+    if !images {
+        return None;
+    }
+
+    let image = clipboard.get_image().ok()?;
+    Some(ClipboardMsg::Image {
+        width: image.width as u32,
+        height: image.height as u32,
+        rgba: image.bytes.into_owned(),
+    })
+}
+
+// Tells whether the clipboard may have changed without reading it, as reading an
+// image copies all of it. None if that can't be told
+struct Changes {
+    // Told whenever the clipboard gets a new owner, which apps take on every copy
+    #[cfg(target_os = "linux")]
+    x11: Option<x11rb::rust_connection::RustConnection>,
+    #[cfg(target_os = "windows")]
+    seq: Option<std::num::NonZeroU32>,
+}
+
+#[cfg(target_os = "linux")]
+impl Changes {
+    fn new() -> Self {
+        Self { x11: watch_x11() }
+    }
+
+    fn changed(&mut self) -> Option<bool> {
+        use x11rb::connection::Connection;
+
+        let mut changed = false;
+        loop {
+            match self.x11.as_ref()?.poll_for_event() {
+                Ok(Some(_)) => changed = true,
+                Ok(None) => return Some(changed),
+                Err(_) => {
+                    self.x11 = None;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn watch_x11() -> Option<x11rb::rust_connection::RustConnection> {
+    use x11rb::{
+        connection::Connection,
+        protocol::{
+            xfixes::{ConnectionExt as _, SelectionEventMask},
+            xproto::ConnectionExt as _,
+        },
+    };
+
+    let (conn, screen) = x11rb::connect(None).ok()?;
+    let root = conn.setup().roots[screen].root;
+    let clipboard = conn
+        .intern_atom(false, b"CLIPBOARD")
+        .ok()?
+        .reply()
+        .ok()?
+        .atom;
+    conn.xfixes_query_version(5, 0).ok()?.reply().ok()?;
+    conn.xfixes_select_selection_input(
+        root,
+        clipboard,
+        SelectionEventMask::SET_SELECTION_OWNER
+            | SelectionEventMask::SELECTION_WINDOW_DESTROY
+            | SelectionEventMask::SELECTION_CLIENT_CLOSE,
+    )
+    .ok()?;
+    conn.flush().ok()?;
+
+    Some(conn)
+}
+
+#[cfg(target_os = "windows")]
+impl Changes {
+    fn new() -> Self {
+        Self {
+            seq: clipboard_win::raw::seq_num(),
+        }
+    }
+
+    fn changed(&mut self) -> Option<bool> {
+        let seq = clipboard_win::raw::seq_num()?;
+        Some(self.seq.replace(seq) != Some(seq))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+impl Changes {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn changed(&mut self) -> Option<bool> {
+        None
+    }
+}
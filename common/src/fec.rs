@@ -0,0 +1,268 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    clock,
+    msgs::{Parity, RTMsg},
+};
+
+// Number of received packets kept around to repair others from
+const HISTORY: usize = 1024;
+// Number of parity packets kept waiting for a retransmission to make them useful
+const MAX_PENDING_PARITY: usize = 64;
+
+fn xor_into(acc: &mut Vec<u8>, b: &[u8]) {
+    if acc.len() < b.len() {
+        acc.resize(b.len(), 0);
+    }
+
+    for (a, b) in acc.iter_mut().zip(b) {
+        *a ^= b;
+    }
+}
+
+// Sends an XOR parity packet after every group of packets, which lets the
+// receiver repair any single loss within the group without a nack
+pub struct FecEncoder {
+    group_size: usize,
+    first_seq: i64,
+    count: u16,
+    len_xor: u32,
+    data: Vec<u8>,
+}
+
+impl FecEncoder {
+    pub fn new(group_size: usize) -> Self {
+        Self {
+            group_size,
+            first_seq: 0,
+            count: 0,
+            len_xor: 0,
+            data: vec![],
+        }
+    }
+
+    // Adds a sent packet, returning a parity packet once the group is full
+    pub fn add(&mut self, msg: &RTMsg) -> Option<RTMsg> {
+        if self.count == 0 {
+            self.first_seq = msg.seq;
+        }
+
+        let b = rmp_serde::to_vec(msg).unwrap();
+        xor_into(&mut self.data, &b);
+        self.len_xor ^= b.len() as u32;
+        self.count += 1;
+
+        if self.count as usize >= self.group_size {
+            return self.flush();
+        }
+
+        None
+    }
+
+    // Returns a parity packet for the group so far, so it doesn't wait on the next frame
+    pub fn flush(&mut self) -> Option<RTMsg> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let parity = RTMsg {
+            seq: -1,
+            is_audio: false,
+            sent_us: clock::now_us(),
+            frame: None,
+            audio: None,
+            parity: Some(Parity {
+                first_seq: self.first_seq,
+                count: self.count,
+                len_xor: self.len_xor,
+            }),
+            data: std::mem::take(&mut self.data),
+        };
+
+        self.count = 0;
+        self.len_xor = 0;
+
+        Some(parity)
+    }
+}
+
+// Repairs lost packets using the parity packets sent by FecEncoder
+pub struct FecDecoder {
+    received: HashMap<i64, Vec<u8>>,
+    order: VecDeque<i64>,
+    pending: Vec<RTMsg>,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self {
+            received: HashMap::new(),
+            order: VecDeque::new(),
+            pending: vec![],
+        }
+    }
+
+    fn record(&mut self, msg: &RTMsg) {
+        if self.received.contains_key(&msg.seq) {
+            return;
+        }
+
+        self.received
+            .insert(msg.seq, rmp_serde::to_vec(msg).unwrap());
+        self.order.push_back(msg.seq);
+        while self.order.len() > HISTORY {
+            let seq = self.order.pop_front().unwrap();
+            self.received.remove(&seq);
+        }
+    }
+
+    // Records a media packet, returning any packets it allowed to be repaired
+    pub fn on_packet(&mut self, msg: &RTMsg) -> Vec<RTMsg> {
+        self.record(msg);
+
+        let (covering, rest): (Vec<RTMsg>, Vec<RTMsg>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| {
+                let parity = p.parity.unwrap();
+                msg.seq >= parity.first_seq && msg.seq < parity.first_seq + parity.count as i64
+            });
+        self.pending = rest;
+
+        covering
+            .into_iter()
+            .flat_map(|p| self.on_parity(p))
+            .collect()
+    }
+
+    // Returns the lost packet if the parity packet can repair it
+    pub fn on_parity(&mut self, msg: RTMsg) -> Option<RTMsg> {
+        let parity = msg.parity?;
+
+        let group = parity.first_seq..parity.first_seq + parity.count as i64;
+        let missing: Vec<i64> = group
+            .clone()
+            .filter(|seq| !self.received.contains_key(seq))
+            .collect();
+
+        match missing.len() {
+            0 => None,
+            1 => {
+                let mut data = msg.data;
+                let mut len = parity.len_xor;
+                for seq in group.filter(|seq| *seq != missing[0]) {
+                    let b = &self.received[&seq];
+                    xor_into(&mut data, b);
+                    len ^= b.len() as u32;
+                }
+                data.truncate(len as usize);
+
+                let repaired: RTMsg = rmp_serde::from_slice(&data).ok()?;
+                self.record(&repaired);
+                Some(repaired)
+            }
+            _ => {
+                // Wait in case a retransmission leaves only one missing
+                self.pending.push(msg);
+                if self.pending.len() > MAX_PENDING_PARITY {
+                    self.pending.remove(0);
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Default for FecDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq: i64, len: usize) -> RTMsg {
+        RTMsg {
+            seq,
+            is_audio: false,
+            sent_us: 1000 + seq as u64,
+            frame: None,
+            audio: None,
+            parity: None,
+            data: (0..len).map(|i| (i as i64 * 7 + seq) as u8).collect(),
+        }
+    }
+
+    // Sends the packets through an encoder, returning its parity packets
+    fn encode(packets: &[RTMsg], group_size: usize) -> Vec<RTMsg> {
+        let mut enc = FecEncoder::new(group_size);
+        let mut parity: Vec<RTMsg> = packets.iter().filter_map(|p| enc.add(p)).collect();
+        parity.extend(enc.flush());
+        parity
+    }
+
+    #[test]
+    fn repairs_single_loss_of_any_length() {
+        let packets: Vec<RTMsg> = [10, 300, 0, 1200]
+            .iter()
+            .enumerate()
+            .map(|(i, len)| packet(i as i64, *len))
+            .collect();
+        let parity = encode(&packets, 4).pop().unwrap();
+
+        for lost in 0..packets.len() {
+            let mut dec = FecDecoder::new();
+            for p in packets.iter().filter(|p| p.seq != lost as i64) {
+                assert!(dec.on_packet(p).is_empty());
+            }
+
+            let repaired = dec.on_parity(parity.clone()).unwrap();
+            assert_eq!(repaired.seq, lost as i64);
+            assert_eq!(repaired.sent_us, packets[lost].sent_us);
+            assert_eq!(repaired.data, packets[lost].data);
+        }
+    }
+
+    #[test]
+    fn nothing_to_repair_without_loss() {
+        let packets: Vec<RTMsg> = (0..3).map(|seq| packet(seq, 50)).collect();
+        let parity = encode(&packets, 3).pop().unwrap();
+
+        let mut dec = FecDecoder::new();
+        for p in &packets {
+            dec.on_packet(p);
+        }
+        assert!(dec.on_parity(parity).is_none());
+    }
+
+    #[test]
+    fn flush_covers_partial_group() {
+        let packets: Vec<RTMsg> = (5..7).map(|seq| packet(seq, 80)).collect();
+        let parity = encode(&packets, 4);
+        assert_eq!(parity.len(), 1);
+        let p = parity[0].parity.unwrap();
+        assert_eq!((p.first_seq, p.count), (5, 2));
+
+        let mut dec = FecDecoder::new();
+        dec.on_packet(&packets[1]);
+        assert_eq!(dec.on_parity(parity[0].clone()).unwrap().seq, 5);
+    }
+
+    #[test]
+    fn repairs_once_retransmission_leaves_one_missing() {
+        let packets: Vec<RTMsg> = (0..4).map(|seq| packet(seq, 100)).collect();
+        let parity = encode(&packets, 4).pop().unwrap();
+
+        let mut dec = FecDecoder::new();
+        dec.on_packet(&packets[0]);
+        dec.on_packet(&packets[3]);
+        // Two missing, so the parity waits
+        assert!(dec.on_parity(parity).is_none());
+
+        let repaired = dec.on_packet(&packets[1]);
+        assert_eq!(repaired.len(), 1);
+        assert_eq!(repaired[0].seq, 2);
+        assert_eq!(repaired[0].data, packets[2].data);
+    }
+}
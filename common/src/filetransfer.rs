@@ -0,0 +1,653 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::chan::{SubChanReader, SubChanWriter};
+
+const CHUNK_LEN: usize = 64 << 10;
+
+// How far the display can get ahead of what the capture has written, so a big
+// file isn't queued up in memory
+const WINDOW: u64 = 4 << 20;
+
+// Sent both ways over ChannelId::FileTransfer. Files go from the display to the capture,
+// one at a time
+#[derive(Serialize, Deserialize)]
+pub enum FileMsg {
+    // Sent by the display to start sending a file, or to carry on from an earlier attempt
+    Offer {
+        id: u64,
+        name: String,
+        size: u64,
+        sha256: [u8; 32],
+    },
+    // Sent by the capture with how much of the file it already has
+    Accept {
+        id: u64,
+        offset: u64,
+    },
+    Chunk {
+        id: u64,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    // Sent by the capture with how much it has written
+    Ack {
+        id: u64,
+        received: u64,
+    },
+    // Sent by the capture once the file has been checked and saved
+    Done {
+        id: u64,
+    },
+    // Sent by the capture when it gives up on a file
+    Failed {
+        id: u64,
+        reason: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferStatus {
+    Queued,
+    // Hashing the file before offering it
+    Checking,
+    Sending,
+    Done,
+    Failed(String),
+}
+
+// A file sent by the display, for showing progress
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    pub name: String,
+    pub size: u64,
+    // Written by the capture so far
+    pub received: u64,
+    pub status: TransferStatus,
+}
+
+// Sends dropped files to the capture, one at a time
+pub struct FileSender {
+    queue: Sender<(usize, PathBuf)>,
+    pub transfers: Arc<Mutex<Vec<Transfer>>>,
+}
+
+impl FileSender {
+    pub fn new(writer: SubChanWriter, mut reader: SubChanReader) -> Self {
+        let (tx, rx) = mpsc::channel();
+        // Stops when the connection ends
+        thread::spawn(move || {
+            while let Ok(msg) = rmp_serde::from_read::<_, FileMsg>(&mut reader) {
+                if tx.send(msg).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let transfers = Arc::new(Mutex::new(Vec::new()));
+        let (queue, files) = mpsc::channel::<(usize, PathBuf)>();
+        let s_transfers = transfers.clone();
+        thread::spawn(move || {
+            let mut sender = Sending {
+                writer,
+                replies: rx,
+                transfers: s_transfers,
+            };
+            for (i, path) in files {
+                if let Err(e) = sender.send(i, &path) {
+                    sender.set_status(i, TransferStatus::Failed(e.to_string()));
+                }
+            }
+        });
+
+        Self { queue, transfers }
+    }
+
+    pub fn send(&self, path: PathBuf) {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return,
+        };
+
+        let mut transfers = self.transfers.lock().unwrap();
+        transfers.push(Transfer {
+            name,
+            size: 0,
+            received: 0,
+            status: TransferStatus::Queued,
+        });
+        self.queue.send((transfers.len() - 1, path)).unwrap();
+    }
+}
+
+struct Sending {
+    writer: SubChanWriter,
+    replies: Receiver<FileMsg>,
+    transfers: Arc<Mutex<Vec<Transfer>>>,
+}
+
+impl Sending {
+    // Transfers are numbered by where they are in the list
+    fn send(&mut self, i: usize, path: &Path) -> io::Result<()> {
+        let id = i as u64;
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        self.transfers.lock().unwrap()[i].size = size;
+
+        self.set_status(i, TransferStatus::Checking);
+        let sha256 = hash(&mut file)?;
+
+        self.set_status(i, TransferStatus::Sending);
+        let name = self.transfers.lock().unwrap()[i].name.clone();
+        self.write(&FileMsg::Offer {
+            id,
+            name,
+            size,
+            sha256,
+        })?;
+
+        let mut sent = match self.reply(id)? {
+            FileMsg::Accept { offset, .. } => offset,
+            _ => return Ok(()),
+        };
+        self.transfers.lock().unwrap()[i].received = sent;
+
+        file.seek(SeekFrom::Start(sent))?;
+        let mut acked = sent;
+        let mut buf = vec![0; CHUNK_LEN];
+        while sent < size {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file shrank while sending",
+                ));
+            }
+            self.write(&FileMsg::Chunk {
+                id,
+                data: buf[..n].to_vec(),
+            })?;
+            sent += n as u64;
+
+            while sent - acked > WINDOW {
+                match self.reply(id)? {
+                    FileMsg::Ack { received, .. } => {
+                        acked = received;
+                        self.transfers.lock().unwrap()[i].received = received;
+                    }
+                    _ => return Ok(()),
+                }
+            }
+        }
+
+        // Wait for the capture to check it
+        loop {
+            match self.reply(id)? {
+                FileMsg::Ack { received, .. } => {
+                    self.transfers.lock().unwrap()[i].received = received
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Waits for the capture to answer about this file. Done and Failed update the
+    // transfer's status
+    fn reply(&mut self, id: u64) -> io::Result<FileMsg> {
+        loop {
+            let msg = self
+                .replies
+                .recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "capture went away"))?;
+
+            let i = id as usize;
+            match msg {
+                FileMsg::Accept { id: r, .. } | FileMsg::Ack { id: r, .. } if r == id => {
+                    return Ok(msg)
+                }
+                FileMsg::Done { id: r } if r == id => {
+                    self.set_status(i, TransferStatus::Done);
+                    return Ok(msg);
+                }
+                FileMsg::Failed { id: r, ref reason } if r == id => {
+                    self.set_status(i, TransferStatus::Failed(reason.clone()));
+                    return Ok(msg);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn write(&mut self, msg: &FileMsg) -> io::Result<()> {
+        self.writer.write_all(&rmp_serde::to_vec(msg).unwrap())
+    }
+
+    fn set_status(&self, i: usize, status: TransferStatus) {
+        self.transfers.lock().unwrap()[i].status = status;
+    }
+}
+
+// Saves files from the display into dir. Partly received files are kept as
+// <name>.<hash>.part, so sending the same file again carries on where it stopped
+pub fn start_file_receiver(
+    mut writer: SubChanWriter,
+    mut reader: SubChanReader,
+    dir: PathBuf,
+    allowed: bool,
+) {
+    thread::spawn(move || {
+        let mut current: Option<Incoming> = None;
+        loop {
+            // Stops when the connection ends
+            let Ok(msg) = rmp_serde::from_read::<_, FileMsg>(&mut reader) else {
+                return;
+            };
+            let id = match msg {
+                FileMsg::Offer { id, .. } | FileMsg::Chunk { id, .. } => id,
+                _ => continue,
+            };
+
+            let reply = match msg {
+                FileMsg::Offer { .. } if !allowed => Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the capture only allows viewing",
+                )),
+                FileMsg::Offer {
+                    name, size, sha256, ..
+                } => Incoming::start(&dir, id, &name, size, sha256).map(|incoming| {
+                    info!(
+                        "receiving {:?} ({} bytes, {} already received)",
+                        incoming.name, incoming.size, incoming.received
+                    );
+                    let offset = incoming.received;
+                    current = Some(incoming);
+                    FileMsg::Accept { id, offset }
+                }),
+                FileMsg::Chunk { data, .. } => match &mut current {
+                    Some(incoming) if incoming.id == id => incoming.write(&data),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let reply = match reply {
+                Ok(reply) => reply,
+                Err(e) => {
+                    error!("failed to receive file: {}", e);
+                    current = None;
+                    FileMsg::Failed {
+                        id,
+                        reason: e.to_string(),
+                    }
+                }
+            };
+            if writer
+                .write_all(&rmp_serde::to_vec(&reply).unwrap())
+                .is_err()
+            {
+                return;
+            }
+
+            // Finish it off, including when it was already fully received
+            if let Some(incoming) = current.take_if(|c| c.received == c.size) {
+                let reply = match incoming.finish(&dir) {
+                    Ok(path) => {
+                        info!("received {:?}", path);
+                        FileMsg::Done { id }
+                    }
+                    Err(e) => {
+                        error!("failed to receive file: {}", e);
+                        FileMsg::Failed {
+                            id,
+                            reason: e.to_string(),
+                        }
+                    }
+                };
+                if writer
+                    .write_all(&rmp_serde::to_vec(&reply).unwrap())
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+struct Incoming {
+    id: u64,
+    name: String,
+    size: u64,
+    sha256: [u8; 32],
+    part: PathBuf,
+    file: File,
+    received: u64,
+}
+
+impl Incoming {
+    fn start(dir: &Path, id: u64, name: &str, size: u64, sha256: [u8; 32]) -> io::Result<Self> {
+        // Only keep the file name, so the display can't write anywhere else
+        let name = match Path::new(name).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid file name",
+                ))
+            }
+        };
+
+        fs::create_dir_all(dir)?;
+        let part = dir.join(format!("{}.{}.part", name, hex(&sha256[..8])));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&part)?;
+
+        // Start again if it somehow got longer than the file
+        let mut received = file.metadata()?.len();
+        if received > size {
+            file.set_len(0)?;
+            received = 0;
+        }
+
+        Ok(Self {
+            id,
+            name,
+            size,
+            sha256,
+            part,
+            file,
+            received,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<FileMsg> {
+        if self.received + data.len() as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sent more than the file's size",
+            ));
+        }
+
+        self.file.write_all(data)?;
+        self.received += data.len() as u64;
+        Ok(FileMsg::Ack {
+            id: self.id,
+            received: self.received,
+        })
+    }
+
+    // Checks the whole file against its hash and moves it into place, returning
+    // where it ended up
+    fn finish(mut self, dir: &Path) -> io::Result<PathBuf> {
+        self.file.seek(SeekFrom::Start(0))?;
+        if hash(&mut self.file)? != self.sha256 {
+            fs::remove_file(&self.part)?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file was corrupted, send it again",
+            ));
+        }
+
+        // Don't overwrite files already there
+        let mut path = dir.join(&self.name);
+        let mut n = 1;
+        while path.exists() {
+            path = dir.join(format!("{} ({})", self.name, n));
+            n += 1;
+        }
+
+        fs::rename(&self.part, &path)?;
+        Ok(path)
+    }
+}
+
+fn hash(file: &mut File) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::chan::{ChannelId, TcpChan};
+
+    // Both ends of a connection over loopback, kept so the channels stay open
+    fn pair() -> (TcpChan, TcpChan) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+        (TcpChan::new(a), TcpChan::new(b))
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("files-{}", rand::random::<u64>()))
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    // Talks to a receiver saving into dir as the display would
+    struct Display {
+        writer: SubChanWriter,
+        reader: SubChanReader,
+        _chans: (TcpChan, TcpChan),
+    }
+
+    impl Display {
+        fn new(dir: &Path, allowed: bool) -> Self {
+            let (a, b) = pair();
+            let (writer, reader) = a.create_subchan(ChannelId::FileTransfer);
+            let (c_writer, c_reader) = b.create_subchan(ChannelId::FileTransfer);
+            start_file_receiver(c_writer, c_reader, dir.into(), allowed);
+            Self {
+                writer,
+                reader,
+                _chans: (a, b),
+            }
+        }
+
+        fn write(&mut self, msg: &FileMsg) {
+            self.writer
+                .write_all(&rmp_serde::to_vec(msg).unwrap())
+                .unwrap();
+        }
+
+        fn read(&mut self) -> FileMsg {
+            rmp_serde::from_read(&mut self.reader).unwrap()
+        }
+
+        // Offers data under name, returning the offset the receiver accepted it from
+        fn offer(&mut self, name: &str, data: &[u8], sha256: [u8; 32]) -> u64 {
+            self.write(&FileMsg::Offer {
+                id: 0,
+                name: name.into(),
+                size: data.len() as u64,
+                sha256,
+            });
+            match self.read() {
+                FileMsg::Accept { id: 0, offset } => offset,
+                _ => panic!("offer wasn't accepted"),
+            }
+        }
+
+        fn chunk(&mut self, data: &[u8]) {
+            self.write(&FileMsg::Chunk {
+                id: 0,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    #[test]
+    fn resumes_from_partial_file() {
+        let dir = temp_dir();
+        let data = contents(300_000);
+        let sha256 = sha256(&data);
+        fs::create_dir_all(&dir).unwrap();
+        let part = dir.join(format!("a.bin.{}.part", hex(&sha256[..8])));
+        fs::write(&part, &data[..100_000]).unwrap();
+
+        let mut display = Display::new(&dir, true);
+        assert_eq!(display.offer("a.bin", &data, sha256), 100_000);
+        display.chunk(&data[100_000..]);
+        assert!(matches!(
+            display.read(),
+            FileMsg::Ack {
+                id: 0,
+                received: 300_000
+            }
+        ));
+        assert!(matches!(display.read(), FileMsg::Done { id: 0 }));
+
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), data);
+        assert!(!part.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_file_is_rejected() {
+        let dir = temp_dir();
+        let data = contents(1000);
+        let mut wrong = sha256(&data);
+        wrong[31] ^= 1;
+
+        let mut display = Display::new(&dir, true);
+        assert_eq!(display.offer("a.bin", &data, wrong), 0);
+        display.chunk(&data);
+        assert!(matches!(display.read(), FileMsg::Ack { .. }));
+        assert!(matches!(display.read(), FileMsg::Failed { id: 0, .. }));
+
+        // Nothing kept to resume from either
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_cant_leave_the_directory() {
+        let dir = temp_dir();
+        let data = contents(10);
+
+        let mut display = Display::new(&dir.join("inner"), true);
+        assert_eq!(display.offer("../escaped.bin", &data, sha256(&data)), 0);
+        display.chunk(&data);
+        assert!(matches!(display.read(), FileMsg::Ack { .. }));
+        assert!(matches!(display.read(), FileMsg::Done { id: 0 }));
+
+        assert_eq!(fs::read(dir.join("inner/escaped.bin")).unwrap(), data);
+        assert!(!dir.join("escaped.bin").exists());
+
+        // Nothing left once the directories are stripped
+        display.write(&FileMsg::Offer {
+            id: 0,
+            name: "..".into(),
+            size: 10,
+            sha256: sha256(&data),
+        });
+        assert!(matches!(display.read(), FileMsg::Failed { id: 0, .. }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sending_past_the_size_fails() {
+        let dir = temp_dir();
+        let data = contents(100);
+
+        let mut display = Display::new(&dir, true);
+        assert_eq!(display.offer("a.bin", &data[..50], sha256(&data[..50])), 0);
+        display.chunk(&data);
+        assert!(matches!(display.read(), FileMsg::Failed { id: 0, .. }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn part_longer_than_the_file_starts_again() {
+        let dir = temp_dir();
+        let data = contents(100);
+        let sha256 = sha256(&data);
+        fs::create_dir_all(&dir).unwrap();
+        let part = dir.join(format!("a.bin.{}.part", hex(&sha256[..8])));
+        fs::write(&part, contents(200)).unwrap();
+
+        let mut display = Display::new(&dir, true);
+        assert_eq!(display.offer("a.bin", &data, sha256), 0);
+        display.chunk(&data);
+        assert!(matches!(display.read(), FileMsg::Ack { .. }));
+        assert!(matches!(display.read(), FileMsg::Done { id: 0 }));
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn view_only_rejects_offers() {
+        let dir = temp_dir();
+        let mut display = Display::new(&dir, false);
+        display.write(&FileMsg::Offer {
+            id: 0,
+            name: "a.bin".into(),
+            size: 1,
+            sha256: [0; 32],
+        });
+        assert!(matches!(display.read(), FileMsg::Failed { id: 0, .. }));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn sender_sends_whole_file() {
+        let src = temp_dir();
+        let dir = temp_dir();
+        fs::create_dir_all(&src).unwrap();
+        // Bigger than the window, so the sender has to wait for acks
+        let data = contents(WINDOW as usize * 2 + 12345);
+        fs::write(src.join("big.bin"), &data).unwrap();
+
+        let (a, b) = pair();
+        let (writer, reader) = a.create_subchan(ChannelId::FileTransfer);
+        let (c_writer, c_reader) = b.create_subchan(ChannelId::FileTransfer);
+        start_file_receiver(c_writer, c_reader, dir.clone(), true);
+        let sender = FileSender::new(writer, reader);
+        sender.send(src.join("big.bin"));
+
+        let started = Instant::now();
+        while sender.transfers.lock().unwrap()[0].status != TransferStatus::Done {
+            assert!(started.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(
+            sender.transfers.lock().unwrap()[0].received,
+            data.len() as u64
+        );
+        assert_eq!(fs::read(dir.join("big.bin")).unwrap(), data);
+
+        fs::remove_dir_all(&src).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chan;
+
+// Bump whenever a change to the protocol would break older clients, which includes
+// adding a field or variant to anything serialized. Versions 1 to 4 were also used by
+// builds which disagreed on the media and feedback packets, so none of them can be trusted
+pub const PROTOCOL_VERSION: u32 = 5;
+
+// Feature flags, set in SessionHello::features
+pub const FEATURE_AUDIO: u32 = 1 << 0;
+pub const FEATURE_PORT_FORWARD: u32 = 1 << 1;
+pub const FEATURE_GAMEPAD: u32 = 1 << 2;
+pub const FEATURE_CLIPBOARD: u32 = 1 << 3;
+pub const FEATURE_FILE_TRANSFER: u32 = 1 << 4;
+
+// Largest video the display will make a texture for
+const MAX_DIMENSION: u32 = 8192;
+const MAX_FRAME_RATE: u32 = 240;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    H264,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VideoFormat {
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: u32,
+}
+
+impl VideoFormat {
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs(1) / self.frame_rate
+    }
+
+    fn is_valid(&self) -> bool {
+        (1..=MAX_DIMENSION).contains(&self.width)
+            && (1..=MAX_DIMENSION).contains(&self.height)
+            && (1..=MAX_FRAME_RATE).contains(&self.frame_rate)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+// Exchanged by the capture and display on ChannelId::Initial before anything else
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionHello {
+    pub version: u32,
+    // In order of preference
+    pub codecs: Vec<Codec>,
+    // The format being captured, only sent by the capture
+    pub video: Option<VideoFormat>,
+    pub audio: AudioFormat,
+    pub features: u32,
+}
+
+// The parameters both sides agreed on
+#[derive(Clone, Copy, Debug)]
+pub struct Negotiated {
+    pub codec: Codec,
+    pub video: VideoFormat,
+    pub audio: AudioFormat,
+    pub features: u32,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(std::io::Error),
+    Decode(rmp_serde::decode::Error),
+    IncompatibleVersion {
+        ours: u32,
+        theirs: u32,
+    },
+    NoCommonCodec,
+    AudioMismatch {
+        capture: AudioFormat,
+        display: AudioFormat,
+    },
+    MissingVideoFormat,
+    InvalidVideoFormat(VideoFormat),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "handshake failed: {}", e),
+            HandshakeError::Decode(e) => write!(f, "could not decode peer's hello: {}", e),
+            HandshakeError::IncompatibleVersion { ours, theirs } => write!(
+                f,
+                "incompatible protocol version: this client speaks version {} but the peer speaks version {}, update both to the same release",
+                ours, theirs
+            ),
+            HandshakeError::NoCommonCodec => write!(f, "no video codec supported by both sides"),
+            HandshakeError::AudioMismatch { capture, display } => write!(
+                f,
+                "capture sends {} Hz/{} channel audio but display plays {} Hz/{} channel audio",
+                capture.sample_rate, capture.channels, display.sample_rate, display.channels
+            ),
+            HandshakeError::MissingVideoFormat => write!(f, "capture did not send its video format"),
+            HandshakeError::InvalidVideoFormat(v) => write!(
+                f,
+                "capture sends unsupported {}x{} video at {} fps, at most {}x{} at {} fps is supported",
+                v.width, v.height, v.frame_rate, MAX_DIMENSION, MAX_DIMENSION, MAX_FRAME_RATE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(e: std::io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for HandshakeError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        HandshakeError::Decode(e)
+    }
+}
+
+// Sends our hello and waits for the peer's
+pub fn exchange(
+    master: &chan::TcpChan,
+    ours: &SessionHello,
+) -> Result<SessionHello, HandshakeError> {
+    let (mut w, mut r) = master.create_subchan(chan::ChannelId::Initial);
+
+    w.write_all(&rmp_serde::to_vec(ours).unwrap())?;
+    let theirs: SessionHello = rmp_serde::from_read(r.by_ref())?;
+
+    Ok(theirs)
+}
+
+pub fn negotiate(
+    capture: &SessionHello,
+    display: &SessionHello,
+) -> Result<Negotiated, HandshakeError> {
+    if capture.version != display.version {
+        return Err(HandshakeError::IncompatibleVersion {
+            ours: PROTOCOL_VERSION,
+            theirs: if capture.version == PROTOCOL_VERSION {
+                display.version
+            } else {
+                capture.version
+            },
+        });
+    }
+
+    let codec = capture
+        .codecs
+        .iter()
+        .find(|c| display.codecs.contains(c))
+        .ok_or(HandshakeError::NoCommonCodec)?;
+
+    if capture.audio != display.audio {
+        return Err(HandshakeError::AudioMismatch {
+            capture: capture.audio,
+            display: display.audio,
+        });
+    }
+
+    // Everything sizing frames and pacing on the display trusts this
+    let video = capture.video.ok_or(HandshakeError::MissingVideoFormat)?;
+    if !video.is_valid() {
+        return Err(HandshakeError::InvalidVideoFormat(video));
+    }
+
+    Ok(Negotiated {
+        codec: *codec,
+        video,
+        audio: capture.audio,
+        features: capture.features & display.features,
+    })
+}
@@ -1,3 +1,11 @@
 pub mod chan;
+#[cfg(feature = "clipboard")]
+pub mod clipboard;
+pub mod clock;
+pub mod fec;
+pub mod filetransfer;
+pub mod handshake;
 pub mod msgs;
 pub mod portforward;
+pub mod repeater;
+pub mod secure;
@@ -1,17 +1,354 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+// How often each side sends something over udp, even with nothing to send, and how
+// long it can go without hearing from the other before the connection is given up on
+pub const UDP_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// An RTMsg without a frame, audio or parity is a heartbeat, sent when there's
+// nothing else to send
+#[derive(Serialize, Deserialize, Clone)]
 pub struct RTMsg {
     pub seq: i64,
     pub is_audio: bool,
+    // When the packet was first sent, on the capture's clock
+    pub sent_us: u64,
+    // Set on video packets, locating them in their frame
+    pub frame: Option<FrameHeader>,
+    // Set on audio packets
+    pub audio: Option<AudioHeader>,
+    // Set on fec parity packets, which sit outside the seq space
+    pub parity: Option<Parity>,
 
     #[serde(with = "serde_bytes")]
     pub data: Vec<u8>,
 }
 
+// Each encoded frame is split into fragments, which the display reassembles
+// before decoding
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct FrameHeader {
+    pub frame_id: u64,
+    pub fragment: u16,
+    pub fragment_count: u16,
+    pub keyframe: bool,
+    // When the capture started, on the capture's clock
+    pub timestamp_us: u64,
+    // When the capture and then the encode finished, on the capture's clock
+    pub captured_us: u64,
+    pub encoded_us: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct AudioHeader {
+    // Counts audio packets only, so the display can tell which ones it's missing
+    pub seq: u64,
+    // When the last sample in the packet was captured, on the capture's clock
+    pub timestamp_us: u64,
+}
+
+// The group of packets a parity packet was built from
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Parity {
+    pub first_seq: i64,
+    pub count: u16,
+    // XOR of the serialized lengths of the packets in the group
+    pub len_xor: u32,
+}
+
+// Sent by the display back to the capture over udp
+#[derive(Serialize, Deserialize)]
+pub enum Feedback {
+    Nack { seq: i64 },
+    Report(ReceiverReport),
+    // Sent regularly, so the capture notices when the display can't be reached
+    Heartbeat,
+}
+
+// Summarises the packets received since the last report
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ReceiverReport {
+    pub highest_seq: i64,
+    pub received: u32,
+    pub lost: u32,
+    pub jitter_us: f64,
+    // Change in the mean one-way delay since the last report, positive when queues are building
+    pub delay_trend_us: f64,
+}
+
+// Sent by the display over ChannelId::Control
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ControlMsg {
+    // The display lost a frame it couldn't recover, so needs an idr frame to decode again
+    RequestKeyframe,
+
+    // Clock sync probe from the display, answered with a pong
+    Ping {
+        sent_us: u64,
+    },
+    // Sent by the capture with when the ping was received and the pong sent, on its clock
+    Pong {
+        ping_sent_us: u64,
+        received_us: u64,
+        sent_us: u64,
+    },
+
+    // Sent by the display so the capture can show the latency too
+    Latency(LatencyReport),
+}
+
+// How long each stage took for a frame, from starting the capture to presenting it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct LatencyReport {
+    pub capture_us: i64,
+    pub encode_us: i64,
+    pub network_us: i64,
+    pub decode_us: i64,
+    pub present_us: i64,
+    pub total_us: i64,
+    pub rtt_us: i64,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum KeyEvent {
-    Key { letter: char, state: bool },
-    Mouse { x: f64, y: f64 },
-    Click { button: i32, state: bool },
+    // Types a character, for keys without a physical key code
+    Key {
+        letter: char,
+        state: bool,
+    },
+    Mouse {
+        x: f64,
+        y: f64,
+    },
+    // Moves the pointer to a position in the captured image
+    MouseAbs {
+        x: u32,
+        y: u32,
+    },
+    Click {
+        button: MouseButton,
+        state: bool,
+    },
+    Scroll {
+        delta: ScrollDelta,
+    },
+    // Presses or releases a key by its position on the keyboard, so the capture's
+    // layout decides what it types
+    Physical {
+        code: KeyCode,
+        // Held on the display once this event has been applied
+        modifiers: Modifiers,
+        state: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    Back,
+    Forward,
+    Other(u16),
+}
+
+// Positive values scroll up and to the left, as winit reports them
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ScrollDelta {
+    // Wheel notches
+    Lines { x: f32, y: f32 },
+    // From touchpads, which scroll smoothly
+    Pixels { x: f64, y: f64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub meta: bool,
+}
+
+// Physical keys, named after their position on a US layout
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+
+    Backquote,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Digit0,
+    Minus,
+    Equal,
+    Backspace,
+
+    Tab,
+    KeyQ,
+    KeyW,
+    KeyE,
+    KeyR,
+    KeyT,
+    KeyY,
+    KeyU,
+    KeyI,
+    KeyO,
+    KeyP,
+    BracketLeft,
+    BracketRight,
+    Backslash,
+
+    CapsLock,
+    KeyA,
+    KeyS,
+    KeyD,
+    KeyF,
+    KeyG,
+    KeyH,
+    KeyJ,
+    KeyK,
+    KeyL,
+    Semicolon,
+    Quote,
+    Enter,
+
+    ShiftLeft,
+    IntlBackslash,
+    KeyZ,
+    KeyX,
+    KeyC,
+    KeyV,
+    KeyB,
+    KeyN,
+    KeyM,
+    Comma,
+    Period,
+    Slash,
+    ShiftRight,
+
+    ControlLeft,
+    SuperLeft,
+    AltLeft,
+    Space,
+    AltRight,
+    SuperRight,
+    ContextMenu,
+    ControlRight,
+
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+
+    NumLock,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadSubtract,
+    NumpadAdd,
+    NumpadEnter,
+    NumpadDecimal,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+}
+
+// Sent by the display over ChannelId::Gamepad. Pads are numbered by the display,
+// and each gets its own virtual pad on the capture
+#[derive(Serialize, Deserialize, Debug)]
+pub enum GamepadEvent {
+    Connected {
+        pad: u32,
+        name: String,
+    },
+    Disconnected {
+        pad: u32,
+    },
+    Button {
+        pad: u32,
+        button: GamepadButton,
+        pressed: bool,
+    },
+    // Sticks go from -1 to 1 with up and right positive, triggers from 0 to 1
+    Axis {
+        pad: u32,
+        axis: GamepadAxis,
+        value: f32,
+    },
+}
+
+// Sent by the capture over ChannelId::Gamepad
+#[derive(Serialize, Deserialize, Debug)]
+pub enum GamepadFeedback {
+    // Zero magnitudes stop the rumble, and a zero duration plays it until the next one
+    Rumble {
+        pad: u32,
+        strong: u16,
+        weak: u16,
+        duration_ms: u32,
+    },
+}
+
+// Buttons on an xbox style pad, named by their position
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    LeftTrigger,
+    RightTrigger,
 }
@@ -1,102 +1,147 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
-    sync::{Arc, Mutex},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread,
 };
 
 use crate::chan::{self, SubChanReader, SubChanWriter};
+use log::error;
 use serde::{Deserialize, Serialize};
 
+// Forwarded connections are closed by closing their subchannel
 #[derive(Serialize, Deserialize)]
 pub enum ForwardMsg {
     Connect { chan_id: u64, addr: String },
-    Close { chan_id: u64 }, // TODO Not used yet
 }
 
 #[derive(Clone)]
 pub struct PortForwarder {
     master: Arc<Mutex<chan::TcpChan>>,
     control_writer: Arc<Mutex<SubChanWriter>>,
+    // Set for each connection, see new()
+    enabled: Arc<AtomicBool>,
 }
 
 impl PortForwarder {
-    pub fn new(master: Arc<Mutex<chan::TcpChan>>) -> Self {
-        // Create port forwarding control
-        let (control_writer, mut control_reader) = master
-            .lock()
-            .unwrap()
-            .create_subchan(chan::ChannelId::PortForwardControl);
-
-        // Create instance of self
-        let master_c = master.clone();
-        let p = Self {
+    // Only opens the connections the other end asks for while enabled, as they can
+    // reach anything this end can. Connections to the listeners are closed meanwhile
+    pub fn new(master: Arc<Mutex<chan::TcpChan>>, enabled: bool) -> Self {
+        let enabled = Arc::new(AtomicBool::new(enabled));
+        let control_writer = Self::serve(&master, enabled.clone());
+        Self {
             control_writer: Arc::new(Mutex::new(control_writer)),
             master,
-        };
+            enabled,
+        }
+    }
 
-        thread::spawn(move || {
-            let mut open_conns = HashMap::new();
+    // Carries on over the connection now in master, after reconnecting. Listeners are
+    // kept, but connections forwarded over the old connection were closed with it
+    pub fn resume(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        *self.control_writer.lock().unwrap() = Self::serve(&self.master, self.enabled.clone());
+    }
+
+    // Opens the connections asked for by the other end, returning the writer for asking it
+    fn serve(master: &Arc<Mutex<chan::TcpChan>>, enabled: Arc<AtomicBool>) -> SubChanWriter {
+        // Create port forwarding control
+        let master = master.lock().unwrap().clone();
+        let (control_writer, mut control_reader) =
+            master.create_subchan(chan::ChannelId::PortForwardControl);
 
+        thread::spawn(move || {
             loop {
-                let msg: ForwardMsg = rmp_serde::from_read(control_reader.by_ref()).unwrap();
+                // Stops when the connection ends
+                let Ok(msg) = rmp_serde::from_read::<_, ForwardMsg>(control_reader.by_ref()) else {
+                    return;
+                };
 
                 match msg {
                     ForwardMsg::Connect { chan_id, addr } => {
-                        // Open the tcp connection
-                        let mut ts =
-                            TcpStream::connect(addr.to_socket_addrs().unwrap().next().unwrap())
-                                .unwrap();
-                        open_conns.insert(chan_id, ts.try_clone().unwrap());
-
                         // Create a sub channel
-                        let (mut sc_w, mut sc_r) = master_c
-                            .lock()
-                            .unwrap()
-                            .create_subchan(chan::ChannelId::PortForwardSub(chan_id));
-
-                        // Create threads to copy between them
-                        let mut ts_c = ts.try_clone().unwrap();
-                        thread::spawn(move || std::io::copy(&mut sc_r, &mut ts_c));
-                        thread::spawn(move || std::io::copy(&mut ts, &mut sc_w));
-                    }
-
-                    ForwardMsg::Close { chan_id } => {
-                        open_conns[&chan_id]
-                            .shutdown(std::net::Shutdown::Both)
-                            .unwrap();
+                        let (sc_w, sc_r) =
+                            master.create_subchan(chan::ChannelId::PortForwardSub(chan_id));
+
+                        // Dropping the sub channel closes it, so the other end's connection
+                        // is closed too
+                        if !enabled.load(Ordering::Relaxed) {
+                            error!("not allowed to forward to {}", addr);
+                            continue;
+                        }
+                        match connect(&addr) {
+                            Ok(ts) => splice(ts, sc_w, sc_r),
+                            Err(e) => error!("could not forward to {}: {}", addr, e),
+                        }
                     }
                 }
             }
         });
 
-        return p;
+        control_writer
     }
 
-    pub fn request_connection(&self, addr: String) -> (SubChanWriter, SubChanReader) {
+    // Fails while not connected to the other end
+    pub fn request_connection(&self, addr: String) -> io::Result<(SubChanWriter, SubChanReader)> {
         let chan_id = rand::random();
         let b = rmp_serde::to_vec(&ForwardMsg::Connect { chan_id, addr }).unwrap();
-        self.control_writer.lock().unwrap().write_all(&b).unwrap();
+        self.control_writer.lock().unwrap().write_all(&b)?;
 
-        return self
+        Ok(self
             .master
             .lock()
             .unwrap()
-            .create_subchan(chan::ChannelId::PortForwardSub(chan_id));
+            .create_subchan(chan::ChannelId::PortForwardSub(chan_id)))
     }
 
-    pub fn listen_and_forward(&self, listen: SocketAddr, forward: String) {
-        let socket = TcpListener::bind(listen).unwrap();
+    // Fails if listen can't be bound, e.g. when it's already in use
+    pub fn listen_and_forward(&self, listen: SocketAddr, forward: String) -> io::Result<()> {
+        let socket = TcpListener::bind(listen)?;
         let selfc = self.clone();
 
         thread::spawn(move || loop {
-            let (mut ts, _) = socket.accept().unwrap();
-            let (mut w, mut r) = selfc.request_connection(forward.clone());
-
-            let mut ts_c = ts.try_clone().unwrap();
-            thread::spawn(move || std::io::copy(&mut r, &mut ts_c));
-            thread::spawn(move || std::io::copy(&mut ts, &mut w));
+            let (ts, _) = socket.accept().unwrap();
+            // Dropping it closes the connection
+            if !selfc.enabled.load(Ordering::Relaxed) {
+                error!("not forwarding to {}, forwarding isn't allowed", forward);
+                continue;
+            }
+            match selfc.request_connection(forward.clone()) {
+                Ok((w, r)) => splice(ts, w, r),
+                // Dropping it closes the connection
+                Err(e) => error!("could not forward to {}: {}", forward, e),
+            }
         });
+
+        Ok(())
     }
 }
+
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses found"))?;
+    TcpStream::connect(addr)
+}
+
+// Copies between a tcp connection and a sub channel until both directions are done,
+// passing on half closes
+fn splice(mut ts: TcpStream, mut w: SubChanWriter, mut r: SubChanReader) {
+    let mut ts_c = ts.try_clone().unwrap();
+    thread::spawn(move || {
+        // Stop the other direction too if the connection broke
+        let how = match io::copy(&mut r, &mut ts_c) {
+            Ok(_) => Shutdown::Write,
+            Err(_) => Shutdown::Both,
+        };
+        let _ = ts_c.shutdown(how);
+    });
+    thread::spawn(move || {
+        let _ = io::copy(&mut ts, &mut w);
+        w.close();
+    });
+}
@@ -0,0 +1,109 @@
+use std::{io, net::UdpSocket, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Role {
+    Capture,
+    Display,
+}
+
+impl Role {
+    pub fn peer(self) -> Role {
+        match self {
+            Role::Capture => Role::Display,
+            Role::Display => Role::Capture,
+        }
+    }
+}
+
+// How often the hello is sent again while waiting to be paired, in case it was lost
+const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Sent as the first udp packet, so the repeater knows which session a client belongs to.
+// Sending it again while connected to the session starts pairing again, but only with
+// the token from the last Paired, so nobody else with the code can end the session
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+    pub session: String,
+    pub role: Role,
+    pub resume: Option<u64>,
+}
+
+// Sent by the repeater to each client once both ends of the session are present
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Paired {
+    pub token: u64,
+}
+
+// Sent as the first message on the tcp connection, binding it to the udp client
+// which was given the token. Unknown or duplicate connections are closed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TcpHello {
+    pub session: String,
+    pub role: Role,
+    pub token: u64,
+}
+
+pub fn new_session_code() -> String {
+    format!("{:06}", rand::random::<u32>() % 1_000_000)
+}
+
+// Registers with the repeater and waits for it to pair this udp client
+pub fn pair(sock: &UdpSocket, hello: &Hello) -> io::Result<Paired> {
+    sock.set_read_timeout(Some(HELLO_INTERVAL))?;
+    let hello = rmp_serde::to_vec(hello).unwrap();
+    sock.send(&hello)?;
+
+    let mut buf = vec![0; 64];
+    let paired = loop {
+        let size = match sock.recv(&mut buf) {
+            Ok(size) => size,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                sock.send(&hello)?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Ok(paired) = rmp_serde::from_slice(&buf[..size]) {
+            break paired;
+        }
+    };
+
+    sock.set_read_timeout(None)?;
+    Ok(paired)
+}
+
+// How long to wait before trying to reach the repeater again, doubling after each
+// failed attempt
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { next: MIN_BACKOFF }
+    }
+}
+
+impl Backoff {
+    pub fn next_delay(&mut self) -> Duration {
+        let wait = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+
+        // Spread out clients that lost the repeater at the same time
+        wait.mul_f64(rand::random_range(0.75..1.25))
+    }
+
+    pub fn reset(&mut self) {
+        self.next = MIN_BACKOFF;
+    }
+}
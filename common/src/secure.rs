@@ -0,0 +1,631 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use sha2::{Digest, Sha256};
+use snow::{Builder, StatelessTransportState};
+
+// Both sides prove they know the pairing secret, without sending it, and agree on
+// fresh keys for the session. The display also proves it holds its identity key.
+// The relay only ever sees ciphertext
+const NOISE_PARAMS: &str = "Noise_XNpsk0_25519_ChaChaPoly_BLAKE2s";
+const KEY_LEN: usize = 32;
+// In bytes of the key's hash
+const FINGERPRINT_LEN: usize = 16;
+const MAX_NAME_LEN: usize = 64;
+
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PLAINTEXT_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+// Udp packets are sent with the same keys as the tcp stream, so their nonces have
+// the top bit set to keep them apart
+const UDP_NONCE_BIT: u64 = 1 << 63;
+// How far behind the newest udp packet an older one can arrive and still be accepted
+const REPLAY_WINDOW: u64 = 64;
+
+// Without ambiguous characters, so the code can be read out and typed in
+const SECRET_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const SECRET_LEN: usize = 12;
+
+// The code shown by the capture and entered on the display, as <session>-<secret>.
+// Only the session part is sent to the repeater
+#[derive(Clone, Debug)]
+pub struct PairingCode {
+    pub session: String,
+    pub secret: String,
+}
+
+impl PairingCode {
+    // Pairs a session with a new random secret
+    pub fn new(session: String) -> Self {
+        let secret = (0..SECRET_LEN)
+            .map(|_| SECRET_ALPHABET[rand::random_range(0..SECRET_ALPHABET.len())] as char)
+            .collect();
+
+        Self { session, secret }
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        let (session, secret) = code.trim().rsplit_once('-')?;
+        if session.is_empty() || secret.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            session: session.into(),
+            secret: secret.to_lowercase(),
+        })
+    }
+
+    fn psk(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"smoothmirror pairing secret ");
+        hasher.update(self.secret.as_bytes());
+        hasher.finalize().into()
+    }
+
+    // Ties the handshake to the session, so it can't be replayed into another one
+    fn prologue(&self) -> Vec<u8> {
+        [b"smoothmirror session ".as_slice(), self.session.as_bytes()].concat()
+    }
+}
+
+impl fmt::Display for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.session, self.secret)
+    }
+}
+
+#[derive(Debug)]
+pub enum SecureError {
+    Io(std::io::Error),
+    Noise(snow::Error),
+    TooLong,
+    Replayed,
+}
+
+impl fmt::Display for SecureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecureError::Io(e) => {
+                write!(f, "secure handshake failed, check the pairing code: {}", e)
+            }
+            SecureError::Noise(e) => write!(
+                f,
+                "could not authenticate the peer, check the pairing code: {}",
+                e
+            ),
+            SecureError::TooLong => write!(f, "encrypted message is too long"),
+            SecureError::Replayed => write!(f, "packet was already received"),
+        }
+    }
+}
+
+impl std::error::Error for SecureError {}
+
+impl From<std::io::Error> for SecureError {
+    fn from(e: std::io::Error) -> Self {
+        SecureError::Io(e)
+    }
+}
+
+impl From<snow::Error> for SecureError {
+    fn from(e: snow::Error) -> Self {
+        SecureError::Noise(e)
+    }
+}
+
+// Session keys shared by the tcp channel and the udp stream
+pub struct SecureChannel {
+    transport: StatelessTransportState,
+    udp_nonce: AtomicU64,
+    udp_replay: Mutex<ReplayWindow>,
+}
+
+// A display's long term key, so the capture can recognise it across sessions
+pub struct Identity {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl Identity {
+    // Reads the key from the file, or creates a new one there if it doesn't exist yet.
+    // Anyone who can read the file can pass for this display, so it's kept private
+    pub fn load_or_create(path: &Path) -> std::io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(b) if b.len() == 2 * KEY_LEN => {
+                check_private(path)?;
+                Ok(Self {
+                    private: b[..KEY_LEN].to_vec(),
+                    public: b[KEY_LEN..].to_vec(),
+                })
+            }
+            Ok(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{:?} is not an identity key", path),
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let keypair = Builder::new(NOISE_PARAMS.parse().unwrap())
+                    .generate_keypair()
+                    .unwrap();
+                write_private(
+                    path,
+                    &[keypair.private.as_slice(), &keypair.public].concat(),
+                )?;
+
+                Ok(Self {
+                    private: keypair.private,
+                    public: keypair.public,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+}
+
+// Creates the file readable by its owner only
+#[cfg(unix)]
+fn write_private(path: &Path, b: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(b)
+}
+
+// Files in the user's profile aren't readable by other users by default
+#[cfg(not(unix))]
+fn write_private(path: &Path, b: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, b)
+}
+
+#[cfg(unix)]
+fn check_private(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!(
+                "{:?} can be read by other users, make it private with chmod 600",
+                path
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Who the capture is talking to, as proven by the handshake
+#[derive(Clone, Debug)]
+pub struct PeerIdentity {
+    // Chosen by the display, so only to help tell displays apart
+    pub name: String,
+    pub fingerprint: String,
+}
+
+// The name ends up in the approval ui and the trusted displays file, so it can't be
+// allowed to add lines to either or move the cursor around
+fn clean_name(name: &[u8]) -> String {
+    String::from_utf8_lossy(name)
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+// Short enough to compare by eye
+fn fingerprint(public: &[u8]) -> String {
+    let hash = Sha256::digest(public);
+    hash[..FINGERPRINT_LEN]
+        .chunks(2)
+        .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Runs the display's half of the handshake over the tcp connection spliced by the
+// repeater, proving its identity to the capture
+pub fn connect(
+    ts: &mut TcpStream,
+    code: &PairingCode,
+    identity: &Identity,
+    name: &str,
+) -> Result<SecureChannel, SecureError> {
+    let psk = code.psk();
+    let prologue = code.prologue();
+    let mut hs = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .psk(0, &psk)
+        .prologue(&prologue)
+        .local_private_key(&identity.private)
+        .build_initiator()?;
+
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let len = hs.write_message(&[], &mut buf)?;
+    write_frame(ts, &buf[..len])?;
+
+    let msg = read_frame(ts)?;
+    hs.read_message(&msg, &mut buf)?;
+
+    // Our name is only sent once the channel is encrypted
+    let name: String = name.chars().take(MAX_NAME_LEN).collect();
+    let len = hs.write_message(name.as_bytes(), &mut buf)?;
+    write_frame(ts, &buf[..len])?;
+
+    Ok(SecureChannel::new(hs.into_stateless_transport_mode()?))
+}
+
+// Runs the capture's half of the handshake, returning who connected
+pub fn accept(
+    ts: &mut TcpStream,
+    code: &PairingCode,
+) -> Result<(SecureChannel, PeerIdentity), SecureError> {
+    let psk = code.psk();
+    let prologue = code.prologue();
+    let mut hs = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .psk(0, &psk)
+        .prologue(&prologue)
+        .build_responder()?;
+
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let msg = read_frame(ts)?;
+    hs.read_message(&msg, &mut buf)?;
+
+    let len = hs.write_message(&[], &mut buf)?;
+    write_frame(ts, &buf[..len])?;
+
+    let msg = read_frame(ts)?;
+    let len = hs.read_message(&msg, &mut buf)?;
+    let peer = PeerIdentity {
+        name: clean_name(&buf[..len]),
+        fingerprint: fingerprint(hs.get_remote_static().unwrap()),
+    };
+
+    Ok((
+        SecureChannel::new(hs.into_stateless_transport_mode()?),
+        peer,
+    ))
+}
+
+impl SecureChannel {
+    fn new(transport: StatelessTransportState) -> Self {
+        Self {
+            transport,
+            udp_nonce: AtomicU64::new(0),
+            udp_replay: Mutex::new(ReplayWindow::default()),
+        }
+    }
+
+    // Encrypts a udp packet, prefixed with its nonce since packets can be lost or reordered
+    pub fn seal_udp(&self, payload: &[u8]) -> Vec<u8> {
+        let nonce = UDP_NONCE_BIT | self.udp_nonce.fetch_add(1, Ordering::Relaxed);
+
+        let mut out = vec![0; 8 + payload.len() + TAG_LEN];
+        out[..8].copy_from_slice(&nonce.to_be_bytes());
+        let len = self
+            .transport
+            .write_message(nonce, payload, &mut out[8..])
+            .unwrap();
+        out.truncate(8 + len);
+
+        out
+    }
+
+    // Decrypts and authenticates a udp packet, rejecting any that were already seen
+    pub fn open_udp(&self, packet: &[u8]) -> Result<Vec<u8>, SecureError> {
+        if packet.len() < 8 + TAG_LEN {
+            return Err(SecureError::Noise(snow::Error::Decrypt));
+        }
+
+        let nonce = u64::from_be_bytes(packet[..8].try_into().unwrap());
+        if nonce & UDP_NONCE_BIT == 0 {
+            return Err(SecureError::Noise(snow::Error::Decrypt));
+        }
+
+        let mut out = vec![0; packet.len() - 8];
+        let len = self.transport.read_message(nonce, &packet[8..], &mut out)?;
+        out.truncate(len);
+
+        // Only checked once the packet is known to be authentic
+        if !self
+            .udp_replay
+            .lock()
+            .unwrap()
+            .accept(nonce & !UDP_NONCE_BIT)
+        {
+            return Err(SecureError::Replayed);
+        }
+
+        Ok(out)
+    }
+}
+
+#[derive(Default)]
+struct ReplayWindow {
+    // One past the newest nonce seen
+    next: u64,
+    // Bit n is set if nonce next - 1 - n was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            let shift = nonce - self.next + 1;
+            self.seen = match shift < REPLAY_WINDOW {
+                true => (self.seen << shift) | 1,
+                false => 1,
+            };
+            self.next = nonce + 1;
+            return true;
+        }
+
+        let age = self.next - 1 - nonce;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+// Decrypts the frames written by a SecureWriter.
+// Tcp is reliable and ordered, so the nonce is just a counter
+pub struct SecureReader<R> {
+    inner: R,
+    secure: Arc<SecureChannel>,
+    nonce: u64,
+    r_buf: VecDeque<u8>,
+}
+
+impl<R: Read> SecureReader<R> {
+    pub fn new(inner: R, secure: Arc<SecureChannel>) -> Self {
+        Self {
+            inner,
+            secure,
+            nonce: 0,
+            r_buf: VecDeque::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for SecureReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.r_buf.is_empty() {
+            let msg = read_frame(&mut self.inner)?;
+            let mut out = vec![0; msg.len()];
+            let len = self
+                .secure
+                .transport
+                .read_message(self.nonce, &msg, &mut out)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            self.nonce += 1;
+
+            self.r_buf.extend(&out[..len]);
+        }
+
+        self.r_buf.read(buf)
+    }
+}
+
+// Encrypts everything written into length prefixed frames
+pub struct SecureWriter<W> {
+    inner: W,
+    secure: Arc<SecureChannel>,
+    nonce: u64,
+}
+
+impl<W: Write> SecureWriter<W> {
+    pub fn new(inner: W, secure: Arc<SecureChannel>) -> Self {
+        Self {
+            inner,
+            secure,
+            nonce: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for SecureWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Larger writes are split over several frames
+        let n = buf.len().min(MAX_PLAINTEXT_LEN);
+
+        let mut out = vec![0; n + TAG_LEN];
+        let len = self
+            .secure
+            .transport
+            .write_message(self.nonce, &buf[..n], &mut out)
+            .map_err(std::io::Error::other)?;
+        self.nonce += 1;
+
+        write_frame(&mut self.inner, &out[..len])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_frame(w: &mut impl Write, msg: &[u8]) -> std::io::Result<()> {
+    let len: u16 = msg
+        .len()
+        .try_into()
+        .map_err(|_| std::io::Error::other(SecureError::TooLong))?;
+
+    let mut frame = Vec::with_capacity(2 + msg.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(msg);
+    w.write_all(&frame)
+}
+
+fn read_frame(r: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    r.read_exact(&mut len)?;
+
+    let mut msg = vec![0; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn replay_window_accepts_each_nonce_once() {
+        let mut w = ReplayWindow::default();
+        assert!(w.accept(0));
+        assert!(w.accept(1));
+        assert!(!w.accept(1));
+        assert!(!w.accept(0));
+
+        // Reordered within the window
+        assert!(w.accept(5));
+        assert!(w.accept(3));
+        assert!(!w.accept(3));
+        assert!(w.accept(4));
+        assert!(w.accept(2));
+        assert!(!w.accept(5));
+    }
+
+    #[test]
+    fn replay_window_rejects_too_old() {
+        let mut w = ReplayWindow::default();
+        assert!(w.accept(10));
+        assert!(w.accept(10 + REPLAY_WINDOW - 1));
+        // Still just inside the window
+        assert!(!w.accept(10));
+        assert!(w.accept(11));
+
+        assert!(w.accept(1000));
+        assert!(!w.accept(1000 - REPLAY_WINDOW));
+        assert!(w.accept(1000 - REPLAY_WINDOW + 1));
+    }
+
+    // Runs both halves of the handshake over loopback
+    fn pair(name: &str) -> (SecureChannel, SecureChannel, PeerIdentity, Identity) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let code = PairingCode::new("123456".into());
+
+        let keypair = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        let identity = Identity {
+            private: keypair.private,
+            public: keypair.public,
+        };
+
+        let accept_code = code.clone();
+        let capture = thread::spawn(move || {
+            let (mut ts, _) = listener.accept().unwrap();
+            accept(&mut ts, &accept_code).unwrap()
+        });
+        let mut ts = TcpStream::connect(addr).unwrap();
+        let display = connect(&mut ts, &code, &identity, name).unwrap();
+        let (capture, peer) = capture.join().unwrap();
+
+        (capture, display, peer, identity)
+    }
+
+    #[test]
+    fn handshake_proves_display_identity() {
+        let (_, _, peer, identity) = pair("test display");
+        assert_eq!(peer.name, "test display");
+        assert_eq!(peer.fingerprint, identity.fingerprint());
+    }
+
+    #[test]
+    fn peer_name_cant_add_lines() {
+        let (_, _, peer, _) = pair("evil\nab12:cd34 x\x1b[2J");
+        assert_eq!(peer.name, "evil ab12:cd34 x [2J");
+    }
+
+    #[test]
+    fn udp_packets_open_once() {
+        let (capture, display, _, _) = pair("test display");
+
+        let a = capture.seal_udp(b"first");
+        let b = capture.seal_udp(b"second");
+        assert_eq!(display.open_udp(&b).unwrap(), b"second");
+        assert_eq!(display.open_udp(&a).unwrap(), b"first");
+        assert!(matches!(display.open_udp(&a), Err(SecureError::Replayed)));
+
+        let mut tampered = capture.seal_udp(b"third");
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(matches!(
+            display.open_udp(&tampered),
+            Err(SecureError::Noise(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("identity-{}.key", rand::random::<u64>()));
+        let created = Identity::load_or_create(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let loaded = Identity::load_or_create(&path).unwrap();
+        assert_eq!(loaded.fingerprint(), created.fingerprint());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let err = Identity::load_or_create(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wrong_secret_fails_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let code = PairingCode::new("123456".into());
+        let wrong = PairingCode::new("123456".into());
+
+        let capture = thread::spawn(move || {
+            let (mut ts, _) = listener.accept().unwrap();
+            accept(&mut ts, &code).map(|_| ())
+        });
+        let identity = Identity {
+            private: vec![7; KEY_LEN],
+            public: vec![],
+        };
+        let mut ts = TcpStream::connect(addr).unwrap();
+        let _ = connect(&mut ts, &wrong, &identity, "impostor");
+        drop(ts);
+
+        assert!(matches!(
+            capture.join().unwrap(),
+            Err(SecureError::Noise(_))
+        ));
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use audiopus::{coder::Decoder, packet::Packet, MutSignals};
+use common::clock;
+
+const CHANNELS: usize = 2;
+const SAMPLE_RATE: u64 = 48000;
+// Largest opus frame, 120ms at 48kHz
+const MAX_FRAME_SAMPLES: usize = 5760;
+
+// Bounds on how many packets are held back before playing
+const MIN_DEPTH: usize = 1;
+const MAX_DEPTH: usize = 8;
+// How long playout has to go without an underrun before the depth is lowered
+const STABLE_PERIOD: Duration = Duration::from_secs(5);
+// How far the delay can fall short of the minimum before playout is paused to resync
+const SYNC_TOLERANCE_US: i64 = 20_000;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct AudioStats {
+    pub depth: usize,
+    // Packets that weren't there when they had to be played
+    pub concealed: u64,
+    pub recovered: u64,
+    pub underruns: u64,
+    // Time from capture until the samples currently being output are heard
+    pub delay_us: Option<i64>,
+}
+
+// Reorders audio packets by their sequence number and decodes them as the output
+// device asks for samples, concealing any packets that haven't arrived in time
+pub struct AudioJitterBuffer {
+    decoder: Decoder,
+    packets: BTreeMap<u64, (u64, Vec<u8>)>,
+    next_seq: u64,
+    // Waiting for depth packets before starting playout
+    buffering: bool,
+    decoded: VecDeque<f32>,
+    // Capture timestamp just after the last decoded sample
+    decoded_end_us: u64,
+    // Samples per channel in the last decoded packet, used as the size of concealed ones
+    frame_samples: usize,
+
+    depth: usize,
+    last_depth_change: Instant,
+    // Playout is held back until it's at least this far behind capture, so it lines up
+    // with the video
+    min_delay_us: i64,
+    stats: AudioStats,
+}
+
+impl AudioJitterBuffer {
+    pub fn new() -> Self {
+        Self {
+            decoder: Decoder::new(audiopus::SampleRate::Hz48000, audiopus::Channels::Stereo)
+                .unwrap(),
+            packets: BTreeMap::new(),
+            next_seq: 0,
+            buffering: true,
+            decoded: VecDeque::new(),
+            decoded_end_us: 0,
+            frame_samples: 960,
+            depth: 2,
+            last_depth_change: Instant::now(),
+            min_delay_us: 0,
+            stats: AudioStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, seq: u64, timestamp_us: u64, data: Vec<u8>) {
+        // Too late, it has already been concealed
+        if seq < self.next_seq {
+            return;
+        }
+
+        self.packets.insert(seq, (timestamp_us, data));
+    }
+
+    pub fn set_min_delay(&mut self, min_delay_us: i64) {
+        self.min_delay_us = min_delay_us;
+    }
+
+    // Fills the output with interleaved samples, decoding more packets as needed.
+    // The latency is how long until the output is heard
+    pub fn fill(&mut self, out: &mut [f32], latency: Duration) {
+        while self.decoded.len() < out.len() {
+            if !self.decode_next() {
+                break;
+            }
+        }
+
+        // Work out how old the samples at the front are
+        self.stats.delay_us = match self.decoded.is_empty() {
+            true => None,
+            false => {
+                let queued_us = samples_to_us(self.decoded.len() / CHANNELS);
+                let front_us = self.decoded_end_us as i64 - queued_us as i64;
+                Some(clock::now_us() as i64 + latency.as_micros() as i64 - front_us)
+            }
+        };
+
+        // Play silence until the video catches up
+        if self
+            .stats
+            .delay_us
+            .is_some_and(|d| d + SYNC_TOLERANCE_US < self.min_delay_us)
+        {
+            out.fill(0.);
+            return;
+        }
+
+        let n = out.len().min(self.decoded.len());
+        for (o, s) in out.iter_mut().zip(self.decoded.drain(..n)) {
+            *o = s;
+        }
+        out[n..].fill(0.);
+    }
+
+    // Decodes the next packet into the output queue, returning false while buffering
+    fn decode_next(&mut self) -> bool {
+        if self.buffering {
+            if self.packets.len() < self.depth {
+                return false;
+            }
+
+            let (first_us, _) = self.packets.first_key_value().unwrap().1;
+            if (clock::now_us() as i64 - *first_us as i64) < self.min_delay_us {
+                return false;
+            }
+
+            self.buffering = false;
+            self.next_seq = self.next_seq.max(*self.packets.keys().next().unwrap());
+        }
+
+        // Drop packets to bring the latency back down when they've piled up
+        while self.packets.len() > self.depth + 2
+            && self
+                .stats
+                .delay_us
+                .is_none_or(|d| d > self.min_delay_us + SYNC_TOLERANCE_US)
+        {
+            let (seq, _) = self.packets.pop_first().unwrap();
+            self.next_seq = self.next_seq.max(seq + 1);
+        }
+
+        let mut output = vec![0f32; MAX_FRAME_SAMPLES * CHANNELS];
+        let samples = match self.packets.remove(&self.next_seq) {
+            Some((timestamp_us, packet)) => {
+                let samples = self.decode(Some(&packet), &mut output, false);
+                // The timestamp is taken once the whole packet has been captured
+                self.decoded_end_us = timestamp_us - samples_to_us(samples);
+                samples
+            }
+            None if self.packets.is_empty() => {
+                // Nothing to play, so conceal this packet and wait for more
+                self.stats.underruns += 1;
+                self.buffering = true;
+                if self.depth < MAX_DEPTH {
+                    self.depth += 1;
+                }
+                self.last_depth_change = Instant::now();
+
+                self.stats.concealed += 1;
+                self.decode(None, &mut output[..self.frame_samples * CHANNELS], false)
+            }
+            None => match self.packets.get(&(self.next_seq + 1)).cloned() {
+                // The next packet carries a low bitrate copy of this one
+                Some((_, next)) => {
+                    self.stats.recovered += 1;
+                    self.decode(
+                        Some(&next),
+                        &mut output[..self.frame_samples * CHANNELS],
+                        true,
+                    )
+                }
+                None => {
+                    self.stats.concealed += 1;
+                    self.decode(None, &mut output[..self.frame_samples * CHANNELS], false)
+                }
+            },
+        };
+        self.next_seq += 1;
+
+        if samples > 0 {
+            self.frame_samples = samples;
+        }
+        self.decoded_end_us += samples_to_us(samples);
+        self.decoded.extend(&output[..samples * CHANNELS]);
+
+        if Instant::now().duration_since(self.last_depth_change) > STABLE_PERIOD
+            && self.depth > MIN_DEPTH
+        {
+            self.depth -= 1;
+            self.last_depth_change = Instant::now();
+        }
+
+        !self.buffering
+    }
+
+    // Returns the samples per channel decoded, or 0 if the packet was corrupt
+    fn decode(&mut self, packet: Option<&[u8]>, output: &mut [f32], fec: bool) -> usize {
+        let packet = packet.and_then(|p| Packet::try_from(p).ok());
+
+        match self
+            .decoder
+            .decode_float(packet, MutSignals::try_from(output).unwrap(), fec)
+        {
+            Ok(samples) => samples,
+            Err(e) => {
+                println!("failed to decode audio: {}", e);
+                0
+            }
+        }
+    }
+
+    pub fn stats(&self) -> AudioStats {
+        AudioStats {
+            depth: self.depth,
+            ..self.stats
+        }
+    }
+}
+
+fn samples_to_us(samples: usize) -> u64 {
+    samples as u64 * 1_000_000 / SAMPLE_RATE
+}
+
+impl Default for AudioJitterBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
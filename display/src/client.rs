@@ -1,50 +1,108 @@
 use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Write},
     net::{SocketAddr, UdpSocket},
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use audiopus::{packet::Packet, MutSignals};
-use common::msgs::RTMsg;
+use common::{
+    chan::{ChanState, SubChanWriter, TcpChan},
+    clock,
+    fec::FecDecoder,
+    handshake::VideoFormat,
+    msgs::{
+        ControlMsg, Feedback, FrameHeader, RTMsg, ReceiverReport, UDP_HEARTBEAT_INTERVAL,
+        UDP_TIMEOUT,
+    },
+    repeater::{self, Hello, Paired},
+    secure::SecureChannel,
+};
 use ffmpeg_sys_next::{self as ffmpeg};
 use glium::winit::event_loop::EventLoopProxy;
+use log::debug;
 use socket2::{Domain, Protocol, Socket, Type};
 
-use crate::{ENCODED_HEIGHT, ENCODED_WIDTH, FRAME_DURATION};
+use crate::{audio::AudioJitterBuffer, session::UserEvent};
 
 #[derive(Clone, Copy)]
 struct FFMPEGLater {
     decoder: *mut ffmpeg::AVCodecContext,
-    parser: *mut ffmpeg::AVCodecParserContext,
 }
 
 pub struct Client {
+    sock: UdpSocket,
+    secure: Arc<SecureChannel>,
+    video: VideoFormat,
     ff: Option<FFMPEGLater>,
-    audio_decoder: audiopus::coder::Decoder,
-    decoded_audio: Arc<Mutex<Vec<f32>>>,
-    el_proxy: EventLoopProxy<Vec<u8>>,
+    audio: Arc<Mutex<AudioJitterBuffer>>,
+    el_proxy: EventLoopProxy<UserEvent>,
+
+    control_chan: SubChanWriter,
+    last_keyframe_request: Option<Instant>,
+    // Stops the client when it ends, and is ended by the client if udp stops arriving
+    chan: TcpChan,
 }
 
 unsafe impl Send for Client {}
 
+// A new client is made for each connection, so don't leak the old one's decoder
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(ff) = self.ff.as_mut() {
+            unsafe { ffmpeg::avcodec_free_context(&mut ff.decoder) };
+        }
+    }
+}
+
+// Registers with the repeater and waits for the capture client to be paired. A new
+// socket is used each time, as the repeater passes on everything from one it has
+// already paired
+pub fn connect_udp(server: &str, hello: &Hello) -> io::Result<(UdpSocket, Paired)> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+    #[cfg(not(target_os = "macos"))]
+    socket.set_recv_buffer_size(8 << 20)?;
+
+    let sock_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+    socket.bind(&sock_addr.into())?;
+
+    let sock: UdpSocket = socket.into();
+    sock.connect(server)?;
+    let paired = repeater::pair(&sock, hello)?;
+
+    Ok((sock, paired))
+}
+
 pub fn init_client(
-    decoded_audio: Arc<Mutex<Vec<f32>>>,
-    el_proxy: EventLoopProxy<Vec<u8>>,
+    sock: UdpSocket,
+    secure: Arc<SecureChannel>,
+    video: VideoFormat,
+    audio: Arc<Mutex<AudioJitterBuffer>>,
+    el_proxy: EventLoopProxy<UserEvent>,
+    control_chan: SubChanWriter,
+    chan: TcpChan,
 ) -> Client {
     Client {
+        sock,
+        secure,
+        video,
         ff: None,
-        audio_decoder: audiopus::coder::Decoder::new(
-            audiopus::SampleRate::Hz48000,
-            audiopus::Channels::Stereo,
-        )
-        .unwrap(),
-        decoded_audio,
+        audio,
         el_proxy,
+        control_chan,
+        last_keyframe_request: None,
+        chan,
     }
 }
 
 impl Client {
-    fn consume_nalu(&mut self, mut nals: *mut ffmpeg::AVPacket) {
+    fn consume_nalu(
+        &mut self,
+        mut nals: *mut ffmpeg::AVPacket,
+        header: FrameHeader,
+        received_us: u64,
+    ) {
         let mut t = Instant::now();
         let res = unsafe { ffmpeg::avcodec_send_packet(self.ff.unwrap().decoder, nals) };
         unsafe { ffmpeg::av_packet_free(std::ptr::addr_of_mut!(nals)) };
@@ -78,7 +136,8 @@ impl Client {
             std::slice::from_raw_parts_mut((*yuv_frame).data[2], (*(*yuv_frame).buf[0]).size)
         };
 
-        let mut image = vec![0; (ENCODED_WIDTH * ENCODED_HEIGHT * 4) as usize];
+        let (width, height) = (self.video.width, self.video.height);
+        let mut image = vec![0; (width * height * 4) as usize];
 
         yuvutils_rs::yuv420_to_rgba(
             &mut y_plane,
@@ -88,9 +147,9 @@ impl Client {
             &mut v_plane,
             unsafe { (*yuv_frame).linesize[2] } as u32,
             &mut image,
-            4 * ENCODED_WIDTH,
-            ENCODED_WIDTH,
-            ENCODED_HEIGHT,
+            4 * width,
+            width,
+            height,
             yuvutils_rs::YuvRange::Full,
             yuvutils_rs::YuvStandardMatrix::Bt709,
         );
@@ -115,7 +174,13 @@ impl Client {
 
         // unsafe { ffmpeg::av_frame_free(std::ptr::addr_of_mut!(rgb_frame)) };
 
-        self.el_proxy.send_event(image).unwrap();
+        // Fails once the window has been closed
+        let _ = self.el_proxy.send_event(UserEvent::Frame(DecodedFrame {
+            header,
+            received_us,
+            decoded_us: clock::now_us(),
+            image,
+        }));
 
         println!(
             "took {} us to finish consuming nalus",
@@ -123,124 +188,504 @@ impl Client {
         );
     }
 
-    fn accumulate_nalus(&mut self, mut msg: &[u8]) {
-        let mut pkt = unsafe { ffmpeg::av_packet_alloc() };
-        while msg.len() > 0 {
-            let n = unsafe {
-                ffmpeg::av_parser_parse2(
-                    self.ff.unwrap().parser,
-                    self.ff.unwrap().decoder,
-                    std::ptr::addr_of_mut!((*pkt).data),
-                    std::ptr::addr_of_mut!((*pkt).size),
-                    msg.as_ptr(),
-                    msg.len() as i32,
-                    ffmpeg::AV_NOPTS_VALUE,
-                    ffmpeg::AV_NOPTS_VALUE,
-                    0,
-                )
-            };
+    // Decodes a whole access unit
+    fn decode_frame(&mut self, frame: &[u8], header: FrameHeader) {
+        let received_us = clock::now_us();
 
-            if n < 0 {
-                panic!("av_parser_parse2={}", n);
-            }
+        let pkt = unsafe { ffmpeg::av_packet_alloc() };
+        if unsafe { ffmpeg::av_new_packet(pkt, frame.len() as i32) } < 0 {
+            panic!("could not allocate avpacket for frame");
+        }
+        unsafe { std::ptr::copy_nonoverlapping(frame.as_ptr(), (*pkt).data, frame.len()) };
 
-            msg = &msg[n as usize..];
+        self.consume_nalu(pkt, header, received_us);
+    }
 
-            if unsafe { *pkt }.size > 0 {
-                self.consume_nalu(pkt);
-                pkt = unsafe { ffmpeg::av_packet_alloc() };
-            }
+    // Asks the capture for an idr frame, again if the last one didn't make it
+    fn request_keyframe(&mut self) {
+        if self
+            .last_keyframe_request
+            .is_some_and(|t| Instant::now().duration_since(t) < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return;
         }
+
+        debug!("requesting keyframe");
+        let _ = self
+            .control_chan
+            .write_all(&rmp_serde::to_vec(&ControlMsg::RequestKeyframe).unwrap());
+        self.last_keyframe_request = Some(Instant::now());
     }
 
     pub fn init(&mut self) {
         let codec = unsafe { ffmpeg::avcodec_find_decoder(ffmpeg::AVCodecID::AV_CODEC_ID_H264) };
 
-        let parser = unsafe { ffmpeg::av_parser_init((*codec).id as i32) };
-
         let decoder = unsafe { ffmpeg::avcodec_alloc_context3(codec) };
 
         unsafe {
             ffmpeg::avcodec_open2(decoder, codec, std::ptr::null_mut());
         }
 
-        self.ff = Some(FFMPEGLater { decoder, parser });
+        self.ff = Some(FFMPEGLater { decoder });
     }
 
     pub fn run(&mut self) {
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
-
-        #[cfg(not(target_os = "macos"))]
-        socket.set_recv_buffer_size(8 << 20).unwrap();
-
-        let sock_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        socket.bind(&sock_addr.into()).unwrap();
-
-        let mut sock: UdpSocket = socket.into();
-        sock.connect("dw.superkooks.com:42069").unwrap();
-        sock.send(&vec![1]).unwrap();
-        sock.recv(&mut vec![0]).unwrap();
+        let mut sock = self.sock.try_clone().unwrap();
+        let mut udp_stream = UdpStream::new(self.video.frame_duration(), self.secure.clone());
+        let mut frames = FrameAssembler::new(self.video.frame_duration());
 
-        let mut udp_stream = UdpStream::new();
+        // Wake up regularly to send heartbeats and check the capture is still there
+        sock.set_read_timeout(Some(UDP_HEARTBEAT_INTERVAL / 4))
+            .unwrap();
+        let mut last_heard = Instant::now();
+        let mut last_heartbeat = Instant::now();
 
         let mut t = Instant::now();
-        loop {
+        while self.chan.state() == ChanState::Open {
+            if last_heard.elapsed() > UDP_TIMEOUT {
+                self.chan.fail(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "nothing heard from the capture over udp for {:?}",
+                        UDP_TIMEOUT
+                    ),
+                ));
+                return;
+            }
+            if last_heartbeat.elapsed() > UDP_HEARTBEAT_INTERVAL {
+                udp_stream.send_feedback(&mut sock, &Feedback::Heartbeat);
+                last_heartbeat = Instant::now();
+            }
+
             println!(
                 "Reading packets again after {} us",
                 Instant::now().duration_since(t).as_micros()
             );
             let mut buf = vec![0; 2048];
-            sock.recv(&mut buf).unwrap();
+            // Errors such as timeouts are noticed by the heartbeats
+            let Ok(size) = sock.recv(&mut buf) else {
+                continue;
+            };
             t = Instant::now();
 
-            let msg: RTMsg = rmp_serde::from_slice(&buf).unwrap();
+            // Anything not sent by the capture is dropped
+            let buf = match self.secure.open_udp(&buf[..size]) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    debug!("dropped udp packet: {}", e);
+                    continue;
+                }
+            };
+            let msg: RTMsg = match rmp_serde::from_slice(&buf) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            last_heard = Instant::now();
             for msg in udp_stream.recv(msg, &mut sock) {
-                if msg.is_audio {
-                    let mut output = vec![0f32; 1920 * 4];
-                    self.audio_decoder
-                        .decode_float(
-                            Some(Packet::try_from(&msg.data).unwrap()),
-                            MutSignals::try_from(&mut output).unwrap(),
-                            false,
-                        )
-                        .unwrap();
-                    self.decoded_audio
+                if let Some(header) = msg.audio {
+                    // Decoded when the output device needs it
+                    self.audio
                         .lock()
                         .unwrap()
-                        .extend_from_slice(&output);
-                } else {
-                    self.accumulate_nalus(&msg.data);
+                        .push(header.seq, header.timestamp_us, msg.data);
+                } else if let Some(header) = msg.frame {
+                    for (header, frame) in frames.push(header, msg.data) {
+                        self.decode_frame(&frame, header);
+                    }
+
+                    if frames.waiting_for_keyframe {
+                        self.request_keyframe();
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A decoded frame waiting to be presented
+pub struct DecodedFrame {
+    pub header: FrameHeader,
+    // When the whole frame had arrived and when it was decoded, on our clock
+    pub received_us: u64,
+    pub decoded_us: u64,
+    pub image: Vec<u8>,
+}
+
+// Number of frames to take the minimum transit time over
+const TRANSIT_WINDOW: usize = 120;
+
+#[derive(Clone, Copy, Default)]
+pub struct JitterStats {
+    pub presented: u64,
+    // Frames that arrived after they should have been presented
+    pub late: u64,
+    // Frames skipped because a newer frame was already due
+    pub dropped: u64,
+    pub buffered: usize,
+    // How far the audio lags behind the video, positive when the audio is later
+    pub av_offset_us: Option<i64>,
+}
+
+// Holds decoded frames until their presentation time, which is their capture
+// timestamp plus a delay. The delay is the fastest transit time seen recently and
+// a target latency, or the audio's delay if that's longer so they stay in sync
+pub struct JitterBuffer {
+    target_latency_us: i64,
+    frames: VecDeque<DecodedFrame>,
+    // Local receive time minus capture timestamp, which includes the clock offset
+    transits: VecDeque<i64>,
+    base_delay_us: Option<i64>,
+    audio_delay_us: Option<i64>,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(target_latency: Duration) -> Self {
+        Self {
+            target_latency_us: target_latency.as_micros() as i64,
+            frames: VecDeque::new(),
+            transits: VecDeque::new(),
+            base_delay_us: None,
+            audio_delay_us: None,
+            stats: JitterStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, frame: DecodedFrame) {
+        let now = clock::now_us() as i64;
+
+        self.transits
+            .push_back(now - frame.header.timestamp_us as i64);
+        if self.transits.len() > TRANSIT_WINDOW {
+            self.transits.pop_front();
+        }
+        let min_transit = *self.transits.iter().min().unwrap();
+        self.base_delay_us = Some(min_transit + self.target_latency_us);
+
+        if frame.header.timestamp_us as i64 + self.delay_us() < now {
+            self.stats.late += 1;
+        }
+
+        self.frames.push_back(frame);
+    }
+
+    // Forgets the frames and transit times from an earlier connection
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.transits.clear();
+        self.base_delay_us = None;
+    }
+
+    // The delay the video would have without waiting for the audio
+    pub fn base_delay_us(&self) -> Option<i64> {
+        self.base_delay_us
+    }
+
+    // Sets the time from capture until the audio currently playing is heard
+    pub fn set_audio_delay(&mut self, audio_delay_us: Option<i64>) {
+        self.audio_delay_us = audio_delay_us;
+    }
+
+    fn delay_us(&self) -> i64 {
+        self.base_delay_us
+            .unwrap_or(0)
+            .max(self.audio_delay_us.unwrap_or(0))
+    }
+
+    // Returns the newest frame that is due, skipping any older ones
+    pub fn pop_due(&mut self) -> Option<DecodedFrame> {
+        let now = clock::now_us() as i64;
+        let delay = self.delay_us();
+
+        let mut due = None;
+        while self
+            .frames
+            .front()
+            .is_some_and(|f| f.header.timestamp_us as i64 + delay <= now)
+        {
+            if due.is_some() {
+                self.stats.dropped += 1;
+            }
+            due = self.frames.pop_front();
+        }
+
+        if let Some(frame) = &due {
+            self.stats.presented += 1;
+            self.stats.av_offset_us = self
+                .audio_delay_us
+                .map(|a| a - (now - frame.header.timestamp_us as i64));
+        }
+        due
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        JitterStats {
+            buffered: self.frames.len(),
+            ..self.stats
+        }
+    }
+}
+
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+
+// How many frame durations to wait for the rest of a frame before dropping it
+const FRAME_DEADLINE_FRAMES: u32 = 3;
+
+struct PartialFrame {
+    header: FrameHeader,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    first_seen: Instant,
+}
+
+// Reassembles frames from their fragments, passing on complete frames in order
+struct FrameAssembler {
+    deadline: Duration,
+    frames: BTreeMap<u64, PartialFrame>,
+    // Frames before this have been decoded or dropped
+    next_frame: u64,
+    // Set after a frame is dropped, as the frames after it can't be decoded properly
+    // until the next keyframe
+    waiting_for_keyframe: bool,
+}
+
+impl FrameAssembler {
+    fn new(frame_duration: Duration) -> Self {
+        Self {
+            deadline: frame_duration * FRAME_DEADLINE_FRAMES,
+            frames: BTreeMap::new(),
+            next_frame: 0,
+            waiting_for_keyframe: false,
+        }
+    }
+
+    fn push(&mut self, header: FrameHeader, data: Vec<u8>) -> Vec<(FrameHeader, Vec<u8>)> {
+        if header.frame_id >= self.next_frame && header.fragment < header.fragment_count {
+            let frame = self
+                .frames
+                .entry(header.frame_id)
+                .or_insert_with(|| PartialFrame {
+                    header,
+                    fragments: vec![None; header.fragment_count as usize],
+                    received: 0,
+                    first_seen: Instant::now(),
+                });
+
+            // Fragments disagreeing with the first one about the frame, e.g. from a
+            // capture which restarted its frame ids, are dropped
+            if header.fragment_count == frame.header.fragment_count {
+                let fragment = &mut frame.fragments[header.fragment as usize];
+                if fragment.is_none() {
+                    *fragment = Some(data);
+                    frame.received += 1;
                 }
             }
         }
+
+        // Packets arrive in order, so once a later frame is complete the missing
+        // fragments of earlier ones aren't coming
+        let newest_complete = self
+            .frames
+            .iter()
+            .rev()
+            .find(|(_, f)| f.received == f.fragments.len())
+            .map(|(id, _)| *id);
+
+        let mut out = vec![];
+        while let Some(entry) = self.frames.first_entry() {
+            let id = *entry.key();
+            let frame = entry.get();
+
+            if frame.received == frame.fragments.len() {
+                let frame = entry.remove();
+                if frame.header.keyframe {
+                    self.waiting_for_keyframe = false;
+                }
+                if !self.waiting_for_keyframe {
+                    let data = frame.fragments.into_iter().flatten().flatten().collect();
+                    out.push((frame.header, data));
+                }
+            } else if newest_complete.is_some_and(|c| c > id)
+                || Instant::now().duration_since(frame.first_seen) > self.deadline
+            {
+                debug!(
+                    "dropping frame {} with {}/{} fragments",
+                    id,
+                    frame.received,
+                    frame.fragments.len()
+                );
+                entry.remove();
+                self.waiting_for_keyframe = true;
+            } else {
+                break;
+            }
+
+            self.next_frame = id + 1;
+        }
+
+        out
     }
 }
 
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+// Gathers the statistics sent to the capture in receiver reports
+struct ReceiverStats {
+    last_report: Instant,
+    // Highest seq at the time of the last report
+    base_seq: i64,
+    highest_seq: i64,
+    received: u32,
+
+    jitter_us: f64,
+    last_transit_us: Option<f64>,
+    transit_sum_us: f64,
+    transit_count: u32,
+    last_mean_transit_us: Option<f64>,
+}
+
+impl ReceiverStats {
+    fn new() -> Self {
+        Self {
+            last_report: Instant::now(),
+            base_seq: -1,
+            highest_seq: -1,
+            received: 0,
+            jitter_us: 0.,
+            last_transit_us: None,
+            transit_sum_us: 0.,
+            transit_count: 0,
+            last_mean_transit_us: None,
+        }
+    }
+
+    fn on_packet(&mut self, msg: &RTMsg) {
+        // Retransmitted and reordered packets would skew the delay, so only count new ones
+        if msg.seq <= self.highest_seq {
+            return;
+        }
+        self.highest_seq = msg.seq;
+        self.received += 1;
+
+        // The clocks aren't synchronised, but the offset cancels out in the differences
+        let transit = clock::now_us() as f64 - msg.sent_us as f64;
+        if let Some(last) = self.last_transit_us {
+            // Interarrival jitter, as in RFC 3550
+            self.jitter_us += ((transit - last).abs() - self.jitter_us) / 16.;
+        }
+        self.last_transit_us = Some(transit);
+        self.transit_sum_us += transit;
+        self.transit_count += 1;
+    }
+
+    fn take_report(&mut self) -> Option<ReceiverReport> {
+        if Instant::now().duration_since(self.last_report) < REPORT_INTERVAL || self.highest_seq < 0
+        {
+            return None;
+        }
+
+        let expected = (self.highest_seq - self.base_seq).max(0) as u32;
+        let mean_transit = match self.transit_count {
+            0 => self.last_mean_transit_us,
+            n => Some(self.transit_sum_us / n as f64),
+        };
+        let delay_trend_us = match (mean_transit, self.last_mean_transit_us) {
+            (Some(mean), Some(last)) => mean - last,
+            _ => 0.,
+        };
+
+        let report = ReceiverReport {
+            highest_seq: self.highest_seq,
+            received: self.received,
+            lost: expected.saturating_sub(self.received),
+            jitter_us: self.jitter_us,
+            delay_trend_us,
+        };
+
+        self.last_report = Instant::now();
+        self.base_seq = self.highest_seq;
+        self.received = 0;
+        self.transit_sum_us = 0.;
+        self.transit_count = 0;
+        self.last_mean_transit_us = mean_transit;
+
+        Some(report)
+    }
+}
+
+// How long to wait for a parity packet to repair a loss before nacking it. Parity is sent
+// straight after its group, so this only has to cover reordering
+const FEC_NACK_DELAY: Duration = Duration::from_millis(10);
+
 struct UdpStream {
+    frame_duration: Duration,
+    secure: Arc<SecureChannel>,
     next_seq: i64,
     last_in_seq: Instant,
     rearrange_buf: Vec<RTMsg>,
     nacked_seq: i64,
+    stats: ReceiverStats,
+
+    fec: FecDecoder,
+    // Set once the capture has sent parity, after which nacks are delayed
+    fec_active: bool,
+    pending_nacks: VecDeque<(i64, Instant)>,
 }
 
 impl UdpStream {
-    fn new() -> Self {
+    fn new(frame_duration: Duration, secure: Arc<SecureChannel>) -> Self {
         return Self {
+            frame_duration,
+            secure,
             next_seq: 0,
             last_in_seq: Instant::now(),
             rearrange_buf: vec![],
             nacked_seq: 0,
+            stats: ReceiverStats::new(),
+            fec: FecDecoder::new(),
+            fec_active: false,
+            pending_nacks: VecDeque::new(),
         };
     }
 
     fn recv(&mut self, msg: RTMsg, udp_sock: &mut UdpSocket) -> Vec<RTMsg> {
         let mut out = vec![];
 
+        // Parity packets aren't passed on, but may repair a lost packet
+        let msgs = if msg.parity.is_some() {
+            self.fec_active = true;
+            self.fec.on_parity(msg).into_iter().collect()
+        } else {
+            let repaired = self.fec.on_packet(&msg);
+            std::iter::once(msg).chain(repaired).collect::<Vec<_>>()
+        };
+
+        for msg in msgs {
+            self.order(msg, udp_sock, &mut out);
+        }
+
+        self.send_nacks(udp_sock);
+
+        out
+    }
+
+    fn order(&mut self, msg: RTMsg, udp_sock: &mut UdpSocket, out: &mut Vec<RTMsg>) {
+        self.stats.on_packet(&msg);
+        if let Some(report) = self.stats.take_report() {
+            self.send_feedback(udp_sock, &Feedback::Report(report));
+        }
+
         if Instant::now().duration_since(self.last_in_seq).as_micros()
-            > FRAME_DURATION.as_micros() * 50
+            > self.frame_duration.as_micros() * 50
             && msg.seq - self.next_seq > 1
         {
             self.next_seq = msg.seq;
+            self.rearrange_buf.retain(|m| m.seq >= self.next_seq);
+        }
+
+        // Drop packets we already have, from both a retransmission and a repair
+        if msg.seq < self.next_seq || self.rearrange_buf.iter().any(|m| m.seq == msg.seq) {
+            return;
         }
 
         if msg.seq != self.next_seq {
@@ -250,19 +695,15 @@ impl UdpStream {
                 msg.seq, self.next_seq, self.nacked_seq
             );
 
+            // Give parity a chance to repair the loss first
+            let deadline = match self.fec_active {
+                true => Instant::now() + FEC_NACK_DELAY,
+                false => Instant::now(),
+            };
             for i in self.next_seq.max(self.nacked_seq)..msg.seq {
-                udp_sock
-                    .send(
-                        &rmp_serde::to_vec(&RTMsg {
-                            seq: i,
-                            is_audio: false,
-                            data: vec![],
-                        })
-                        .unwrap(),
-                    )
-                    .unwrap();
+                self.pending_nacks.push_back((i, deadline));
             }
-            self.nacked_seq = msg.seq;
+            self.nacked_seq = self.nacked_seq.max(msg.seq + 1);
 
             self.rearrange_buf.push(msg);
         } else {
@@ -288,7 +729,27 @@ impl UdpStream {
                 break;
             }
         }
+    }
+
+    // Nacks the packets that are still missing once their deadline has passed
+    fn send_nacks(&mut self, udp_sock: &mut UdpSocket) {
+        while let Some((seq, deadline)) = self.pending_nacks.front().copied() {
+            if deadline > Instant::now() {
+                break;
+            }
+            self.pending_nacks.pop_front();
+
+            if seq < self.next_seq || self.rearrange_buf.iter().any(|m| m.seq == seq) {
+                continue;
+            }
+
+            self.send_feedback(udp_sock, &Feedback::Nack { seq });
+        }
+    }
 
-        out
+    fn send_feedback(&self, udp_sock: &mut UdpSocket, feedback: &Feedback) {
+        let buf = rmp_serde::to_vec(feedback).unwrap();
+        // Failing is the same as the packet being lost
+        let _ = udp_sock.send(&self.secure.seal_udp(&buf));
     }
 }
@@ -0,0 +1,115 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use common::{
+    chan::{SubChanReader, SubChanWriter},
+    clock,
+    msgs::{ControlMsg, LatencyReport},
+};
+
+use crate::client::DecodedFrame;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// Number of pongs to pick the fastest from
+const SAMPLE_WINDOW: usize = 16;
+
+// Estimates the offset between the capture's clock and ours from ping/pong exchanges
+pub struct ClockSync {
+    // Round trip time and offset of each recent exchange
+    samples: VecDeque<(i64, i64)>,
+}
+
+impl ClockSync {
+    fn on_pong(&mut self, ping_sent_us: u64, received_us: u64, sent_us: u64) {
+        let now = clock::now_us() as i64;
+        let (t1, t2, t3) = (ping_sent_us as i64, received_us as i64, sent_us as i64);
+
+        let rtt = (now - t1) - (t3 - t2);
+        let offset = ((t2 - t1) + (t3 - now)) / 2;
+
+        self.samples.push_back((rtt, offset));
+        if self.samples.len() > SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    // The exchange with the lowest rtt was the least delayed by queueing, so is
+    // likely the most symmetric
+    fn best(&self) -> Option<(i64, i64)> {
+        self.samples.iter().min_by_key(|(rtt, _)| *rtt).copied()
+    }
+
+    // The capture's clock minus ours
+    pub fn offset_us(&self) -> Option<i64> {
+        self.best().map(|(_, offset)| offset)
+    }
+
+    pub fn rtt_us(&self) -> Option<i64> {
+        self.best().map(|(rtt, _)| rtt)
+    }
+
+    // Breaks down how long a frame took to get from the capture to the screen
+    pub fn report(&self, frame: &DecodedFrame, presented_us: u64) -> Option<LatencyReport> {
+        let (rtt, offset) = self.best()?;
+        let h = frame.header;
+        let on_capture_clock = |t: u64| t as i64 + offset;
+
+        Some(LatencyReport {
+            capture_us: h.captured_us as i64 - h.timestamp_us as i64,
+            encode_us: h.encoded_us as i64 - h.captured_us as i64,
+            network_us: on_capture_clock(frame.received_us) - h.encoded_us as i64,
+            decode_us: frame.decoded_us as i64 - frame.received_us as i64,
+            present_us: presented_us as i64 - frame.decoded_us as i64,
+            total_us: on_capture_clock(presented_us) - h.timestamp_us as i64,
+            rtt_us: rtt,
+        })
+    }
+}
+
+// Pings the capture periodically over the control channel and reads its pongs
+pub fn start_clock_sync(
+    mut writer: SubChanWriter,
+    mut reader: SubChanReader,
+) -> Arc<Mutex<ClockSync>> {
+    let sync = Arc::new(Mutex::new(ClockSync {
+        samples: VecDeque::new(),
+    }));
+
+    // Both threads stop when the connection ends
+    thread::spawn(move || loop {
+        let ping = ControlMsg::Ping {
+            sent_us: clock::now_us(),
+        };
+        if writer
+            .write_all(&rmp_serde::to_vec(&ping).unwrap())
+            .is_err()
+        {
+            return;
+        }
+        thread::sleep(PING_INTERVAL);
+    });
+
+    let r_sync = sync.clone();
+    thread::spawn(move || {
+        while let Ok(msg) = rmp_serde::from_read::<_, ControlMsg>(&mut reader) {
+            if let ControlMsg::Pong {
+                ping_sent_us,
+                received_us,
+                sent_us,
+            } = msg
+            {
+                r_sync
+                    .lock()
+                    .unwrap()
+                    .on_pong(ping_sent_us, received_us, sent_us);
+            }
+        }
+    });
+
+    sync
+}
@@ -0,0 +1,207 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::Parser;
+use common::secure::PairingCode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Parser)]
+#[command(about = "Shows and controls a computer running the capture client")]
+struct Args {
+    /// Pairing code shown by the capture client
+    session: Option<String>,
+
+    /// TOML config file, command line options override it
+    #[arg(long, default_value = "display.toml")]
+    config: PathBuf,
+
+    /// Address of the repeater
+    #[arg(long)]
+    server: Option<String>,
+
+    /// Name shown to the capture when asking to connect
+    #[arg(long)]
+    name: Option<String>,
+    /// File holding this display's identity key, created if it doesn't exist
+    #[arg(long)]
+    identity_file: Option<PathBuf>,
+
+    /// Forward a local port to an address reachable from the capture, as LISTEN=ADDR
+    #[arg(long = "forward", value_parser = parse_forward)]
+    forwards: Vec<Forward>,
+
+    /// Share a usb device with the capture, as PRODUCT or VENDOR:PRODUCT in hex
+    #[arg(long = "usb", value_parser = parse_usb)]
+    usb_devices: Vec<UsbDevice>,
+
+    /// Name of the audio output device
+    #[arg(long)]
+    audio_device: Option<String>,
+
+    /// Delay in ms added on top of the fastest transit time to smooth out network jitter
+    #[arg(long)]
+    target_latency_ms: Option<u32>,
+
+    /// Move the capture's pointer to where the cursor is in the window, instead of by
+    /// the mouse's motion. Can be changed in the settings
+    #[arg(long)]
+    absolute_mouse: Option<bool>,
+    /// Share the clipboard with the capture. Can be changed in the settings
+    #[arg(long)]
+    clipboard: Option<bool>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Forward {
+    pub listen: SocketAddr,
+    pub forward: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct UsbDevice {
+    pub vendor_id: Option<u16>,
+    pub product_id: u16,
+}
+
+impl UsbDevice {
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.product_id == product_id && self.vendor_id.is_none_or(|v| v == vendor_id)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub server: String,
+    pub session: Option<String>,
+    pub name: String,
+    pub identity_file: PathBuf,
+    pub forwards: Vec<Forward>,
+    pub usb_devices: Vec<UsbDevice>,
+    pub audio_device: Option<String>,
+    pub target_latency_ms: u32,
+    pub absolute_mouse: bool,
+    pub clipboard: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: "dw.superkooks.com:42069".into(),
+            session: None,
+            name: std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .unwrap_or_else(|_| "display".into()),
+            identity_file: "display_identity.key".into(),
+            forwards: vec![],
+            usb_devices: vec![],
+            audio_device: None,
+            target_latency_ms: 30,
+            absolute_mouse: false,
+            clipboard: true,
+        }
+    }
+}
+
+impl Config {
+    // Reads the config file and applies the command line options on top.
+    // Exits with a message if either is invalid
+    pub fn load() -> Self {
+        let args = Args::parse();
+
+        let mut config: Config = match std::fs::read_to_string(&args.config) {
+            Ok(s) => toml::from_str(&s).unwrap_or_else(|e| {
+                eprintln!("invalid config file {:?}: {}", args.config, e);
+                std::process::exit(1);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => {
+                eprintln!("could not read config file {:?}: {}", args.config, e);
+                std::process::exit(1);
+            }
+        };
+
+        if let Some(server) = args.server {
+            config.server = server;
+        }
+        if args.session.is_some() {
+            config.session = args.session;
+        }
+        if let Some(name) = args.name {
+            config.name = name;
+        }
+        if let Some(identity_file) = args.identity_file {
+            config.identity_file = identity_file;
+        }
+        config.forwards.extend(args.forwards);
+        config.usb_devices.extend(args.usb_devices);
+        if args.audio_device.is_some() {
+            config.audio_device = args.audio_device;
+        }
+
+        if let Some(target_latency_ms) = args.target_latency_ms {
+            config.target_latency_ms = target_latency_ms;
+        }
+        if let Some(absolute_mouse) = args.absolute_mouse {
+            config.absolute_mouse = absolute_mouse;
+        }
+        if let Some(clipboard) = args.clipboard {
+            config.clipboard = clipboard;
+        }
+
+        match &config.session {
+            None => {
+                eprintln!("no pairing code given, pass the code shown by the capture client");
+                std::process::exit(1);
+            }
+            Some(code) if PairingCode::parse(code).is_none() => {
+                eprintln!(
+                    "invalid pairing code {:?}, expected the whole code shown by the capture client",
+                    code
+                );
+                std::process::exit(1);
+            }
+            _ => {}
+        }
+
+        config
+    }
+
+    pub fn pairing_code(&self) -> PairingCode {
+        PairingCode::parse(self.session.as_ref().unwrap()).unwrap()
+    }
+
+    pub fn target_latency(&self) -> Duration {
+        Duration::from_millis(self.target_latency_ms as u64)
+    }
+}
+
+fn parse_forward(s: &str) -> Result<Forward, String> {
+    let (listen, forward) = s
+        .split_once('=')
+        .ok_or("expected LISTEN=ADDR, e.g. 127.0.0.1:7800=example.com:80")?;
+
+    Ok(Forward {
+        listen: listen
+            .parse()
+            .map_err(|e| format!("invalid listen address {:?}: {}", listen, e))?,
+        forward: forward.into(),
+    })
+}
+
+fn parse_usb(s: &str) -> Result<UsbDevice, String> {
+    let parse = |id: &str| {
+        u16::from_str_radix(id.trim_start_matches("0x"), 16)
+            .map_err(|e| format!("invalid usb id {:?}: {}", id, e))
+    };
+
+    match s.split_once(':') {
+        Some((vendor, product)) => Ok(UsbDevice {
+            vendor_id: Some(parse(vendor)?),
+            product_id: parse(product)?,
+        }),
+        None => Ok(UsbDevice {
+            vendor_id: None,
+            product_id: parse(s)?,
+        }),
+    }
+}
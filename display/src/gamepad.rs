@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::mpsc::{self, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+use common::{
+    chan::{SubChanReader, SubChanWriter},
+    msgs::{GamepadAxis, GamepadButton, GamepadEvent, GamepadFeedback},
+};
+use gilrs::{
+    ff::{BaseEffect, BaseEffectType, Effect, EffectBuilder, Repeat, Ticks},
+    Axis, Button, EventType, GamepadId, Gilrs,
+};
+
+// How long to wait for gamepad events before checking for rumble from the capture
+const RUMBLE_POLL: Duration = Duration::from_millis(5);
+
+// Forwards the local pads to the capture and plays the rumble it sends back
+pub fn start_gamepads(mut writer: SubChanWriter, mut reader: SubChanReader) {
+    let (tx, rx) = mpsc::channel();
+    // Stops when the connection ends
+    thread::spawn(move || {
+        while let Ok(msg) = rmp_serde::from_read::<_, GamepadFeedback>(&mut reader) {
+            if tx.send(msg).is_err() {
+                return;
+            }
+        }
+    });
+
+    // Gilrs has to stay on the thread it was made on
+    thread::spawn(move || {
+        let mut gilrs = match Gilrs::new() {
+            Ok(gilrs) => gilrs,
+            Err(e) => {
+                eprintln!("could not read gamepads: {}", e);
+                return;
+            }
+        };
+
+        // Pads which were plugged in before starting
+        for (id, pad) in gilrs.gamepads() {
+            println!("forwarding gamepad {:?}", pad.name());
+            let ev = GamepadEvent::Connected {
+                pad: usize::from(id) as u32,
+                name: pad.name().into(),
+            };
+            if writer.write_all(&rmp_serde::to_vec(&ev).unwrap()).is_err() {
+                return;
+            }
+        }
+
+        // Dropping an effect stops it
+        let mut rumbles: HashMap<u32, Effect> = HashMap::new();
+        loop {
+            if let Some(ev) = gilrs.next_event_blocking(Some(RUMBLE_POLL)) {
+                if let EventType::Connected = ev.event {
+                    println!("forwarding gamepad {:?}", gilrs.gamepad(ev.id).name());
+                }
+                if let Some(ev) = gamepad_event(&gilrs, ev.id, ev.event) {
+                    if writer.write_all(&rmp_serde::to_vec(&ev).unwrap()).is_err() {
+                        return;
+                    }
+                }
+            }
+
+            // Stop once the connection has ended, so reconnecting doesn't leave it behind
+            loop {
+                let msg = match rx.try_recv() {
+                    Ok(msg) => msg,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                };
+                let GamepadFeedback::Rumble {
+                    pad,
+                    strong,
+                    weak,
+                    duration_ms,
+                } = msg;
+                rumbles.remove(&pad);
+                if strong == 0 && weak == 0 {
+                    continue;
+                }
+
+                let Some(id) = gilrs
+                    .gamepads()
+                    .map(|(id, _)| id)
+                    .find(|id| usize::from(*id) as u32 == pad)
+                else {
+                    continue;
+                };
+                match rumble(&mut gilrs, id, strong, weak, duration_ms) {
+                    Ok(effect) => {
+                        rumbles.insert(pad, effect);
+                    }
+                    Err(e) => eprintln!("failed to rumble gamepad {}: {}", pad, e),
+                }
+            }
+        }
+    });
+}
+
+fn rumble(
+    gilrs: &mut Gilrs,
+    id: GamepadId,
+    strong: u16,
+    weak: u16,
+    duration_ms: u32,
+) -> Result<Effect, gilrs::ff::Error> {
+    let mut builder = EffectBuilder::new();
+    builder
+        .add_effect(BaseEffect {
+            kind: BaseEffectType::Strong { magnitude: strong },
+            ..Default::default()
+        })
+        .add_effect(BaseEffect {
+            kind: BaseEffectType::Weak { magnitude: weak },
+            ..Default::default()
+        })
+        .gamepads(&[id]);
+    // Otherwise it plays until the next rumble replaces it
+    if duration_ms > 0 {
+        builder.repeat(Repeat::For(Ticks::from_ms(duration_ms)));
+    }
+
+    let effect = builder.finish(gilrs)?;
+    effect.play()?;
+    Ok(effect)
+}
+
+fn gamepad_event(gilrs: &Gilrs, id: GamepadId, ev: EventType) -> Option<GamepadEvent> {
+    let pad = usize::from(id) as u32;
+    match ev {
+        EventType::Connected => Some(GamepadEvent::Connected {
+            pad,
+            name: gilrs.gamepad(id).name().into(),
+        }),
+        EventType::Disconnected => Some(GamepadEvent::Disconnected { pad }),
+        EventType::ButtonPressed(b, _) => Some(GamepadEvent::Button {
+            pad,
+            button: button(b)?,
+            pressed: true,
+        }),
+        EventType::ButtonReleased(b, _) => Some(GamepadEvent::Button {
+            pad,
+            button: button(b)?,
+            pressed: false,
+        }),
+        // Triggers are analog buttons to gilrs
+        EventType::ButtonChanged(Button::LeftTrigger2, value, _) => Some(GamepadEvent::Axis {
+            pad,
+            axis: GamepadAxis::LeftTrigger,
+            value,
+        }),
+        EventType::ButtonChanged(Button::RightTrigger2, value, _) => Some(GamepadEvent::Axis {
+            pad,
+            axis: GamepadAxis::RightTrigger,
+            value,
+        }),
+        EventType::AxisChanged(a, value, _) => Some(GamepadEvent::Axis {
+            pad,
+            axis: axis(a)?,
+            value,
+        }),
+        _ => None,
+    }
+}
+
+fn button(button: Button) -> Option<GamepadButton> {
+    match button {
+        Button::South => Some(GamepadButton::South),
+        Button::East => Some(GamepadButton::East),
+        Button::North => Some(GamepadButton::North),
+        Button::West => Some(GamepadButton::West),
+        Button::LeftTrigger => Some(GamepadButton::LeftBumper),
+        Button::RightTrigger => Some(GamepadButton::RightBumper),
+        Button::Select => Some(GamepadButton::Select),
+        Button::Start => Some(GamepadButton::Start),
+        Button::Mode => Some(GamepadButton::Mode),
+        Button::LeftThumb => Some(GamepadButton::LeftThumb),
+        Button::RightThumb => Some(GamepadButton::RightThumb),
+        Button::DPadUp => Some(GamepadButton::DPadUp),
+        Button::DPadDown => Some(GamepadButton::DPadDown),
+        Button::DPadLeft => Some(GamepadButton::DPadLeft),
+        Button::DPadRight => Some(GamepadButton::DPadRight),
+        _ => None,
+    }
+}
+
+fn axis(axis: Axis) -> Option<GamepadAxis> {
+    match axis {
+        Axis::LeftStickX => Some(GamepadAxis::LeftStickX),
+        Axis::LeftStickY => Some(GamepadAxis::LeftStickY),
+        Axis::RightStickX => Some(GamepadAxis::RightStickX),
+        Axis::RightStickY => Some(GamepadAxis::RightStickY),
+        _ => None,
+    }
+}
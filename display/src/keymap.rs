@@ -0,0 +1,124 @@
+use common::msgs::{KeyCode, MouseButton};
+use glium::winit::{event::MouseButton as WinitMouseButton, keyboard::KeyCode as WinitKeyCode};
+
+// Maps winit's physical keys onto the ones the capture can press
+pub fn key_code(code: WinitKeyCode) -> Option<KeyCode> {
+    Some(match code {
+        WinitKeyCode::Escape => KeyCode::Escape,
+        WinitKeyCode::F1 => KeyCode::F1,
+        WinitKeyCode::F2 => KeyCode::F2,
+        WinitKeyCode::F3 => KeyCode::F3,
+        WinitKeyCode::F4 => KeyCode::F4,
+        WinitKeyCode::F5 => KeyCode::F5,
+        WinitKeyCode::F6 => KeyCode::F6,
+        WinitKeyCode::F7 => KeyCode::F7,
+        WinitKeyCode::F8 => KeyCode::F8,
+        WinitKeyCode::F9 => KeyCode::F9,
+        WinitKeyCode::F10 => KeyCode::F10,
+        WinitKeyCode::F11 => KeyCode::F11,
+        WinitKeyCode::F12 => KeyCode::F12,
+        WinitKeyCode::PrintScreen => KeyCode::PrintScreen,
+        WinitKeyCode::ScrollLock => KeyCode::ScrollLock,
+        WinitKeyCode::Backquote => KeyCode::Backquote,
+        WinitKeyCode::Digit1 => KeyCode::Digit1,
+        WinitKeyCode::Digit2 => KeyCode::Digit2,
+        WinitKeyCode::Digit3 => KeyCode::Digit3,
+        WinitKeyCode::Digit4 => KeyCode::Digit4,
+        WinitKeyCode::Digit5 => KeyCode::Digit5,
+        WinitKeyCode::Digit6 => KeyCode::Digit6,
+        WinitKeyCode::Digit7 => KeyCode::Digit7,
+        WinitKeyCode::Digit8 => KeyCode::Digit8,
+        WinitKeyCode::Digit9 => KeyCode::Digit9,
+        WinitKeyCode::Digit0 => KeyCode::Digit0,
+        WinitKeyCode::Minus => KeyCode::Minus,
+        WinitKeyCode::Equal => KeyCode::Equal,
+        WinitKeyCode::Backspace => KeyCode::Backspace,
+        WinitKeyCode::Tab => KeyCode::Tab,
+        WinitKeyCode::KeyQ => KeyCode::KeyQ,
+        WinitKeyCode::KeyW => KeyCode::KeyW,
+        WinitKeyCode::KeyE => KeyCode::KeyE,
+        WinitKeyCode::KeyR => KeyCode::KeyR,
+        WinitKeyCode::KeyT => KeyCode::KeyT,
+        WinitKeyCode::KeyY => KeyCode::KeyY,
+        WinitKeyCode::KeyU => KeyCode::KeyU,
+        WinitKeyCode::KeyI => KeyCode::KeyI,
+        WinitKeyCode::KeyO => KeyCode::KeyO,
+        WinitKeyCode::KeyP => KeyCode::KeyP,
+        WinitKeyCode::BracketLeft => KeyCode::BracketLeft,
+        WinitKeyCode::BracketRight => KeyCode::BracketRight,
+        WinitKeyCode::Backslash => KeyCode::Backslash,
+        WinitKeyCode::CapsLock => KeyCode::CapsLock,
+        WinitKeyCode::KeyA => KeyCode::KeyA,
+        WinitKeyCode::KeyS => KeyCode::KeyS,
+        WinitKeyCode::KeyD => KeyCode::KeyD,
+        WinitKeyCode::KeyF => KeyCode::KeyF,
+        WinitKeyCode::KeyG => KeyCode::KeyG,
+        WinitKeyCode::KeyH => KeyCode::KeyH,
+        WinitKeyCode::KeyJ => KeyCode::KeyJ,
+        WinitKeyCode::KeyK => KeyCode::KeyK,
+        WinitKeyCode::KeyL => KeyCode::KeyL,
+        WinitKeyCode::Semicolon => KeyCode::Semicolon,
+        WinitKeyCode::Quote => KeyCode::Quote,
+        WinitKeyCode::Enter => KeyCode::Enter,
+        WinitKeyCode::ShiftLeft => KeyCode::ShiftLeft,
+        WinitKeyCode::IntlBackslash => KeyCode::IntlBackslash,
+        WinitKeyCode::KeyZ => KeyCode::KeyZ,
+        WinitKeyCode::KeyX => KeyCode::KeyX,
+        WinitKeyCode::KeyC => KeyCode::KeyC,
+        WinitKeyCode::KeyV => KeyCode::KeyV,
+        WinitKeyCode::KeyB => KeyCode::KeyB,
+        WinitKeyCode::KeyN => KeyCode::KeyN,
+        WinitKeyCode::KeyM => KeyCode::KeyM,
+        WinitKeyCode::Comma => KeyCode::Comma,
+        WinitKeyCode::Period => KeyCode::Period,
+        WinitKeyCode::Slash => KeyCode::Slash,
+        WinitKeyCode::ShiftRight => KeyCode::ShiftRight,
+        WinitKeyCode::ControlLeft => KeyCode::ControlLeft,
+        WinitKeyCode::SuperLeft => KeyCode::SuperLeft,
+        WinitKeyCode::AltLeft => KeyCode::AltLeft,
+        WinitKeyCode::Space => KeyCode::Space,
+        WinitKeyCode::AltRight => KeyCode::AltRight,
+        WinitKeyCode::SuperRight => KeyCode::SuperRight,
+        WinitKeyCode::ContextMenu => KeyCode::ContextMenu,
+        WinitKeyCode::ControlRight => KeyCode::ControlRight,
+        WinitKeyCode::Insert => KeyCode::Insert,
+        WinitKeyCode::Delete => KeyCode::Delete,
+        WinitKeyCode::Home => KeyCode::Home,
+        WinitKeyCode::End => KeyCode::End,
+        WinitKeyCode::PageUp => KeyCode::PageUp,
+        WinitKeyCode::PageDown => KeyCode::PageDown,
+        WinitKeyCode::ArrowUp => KeyCode::ArrowUp,
+        WinitKeyCode::ArrowDown => KeyCode::ArrowDown,
+        WinitKeyCode::ArrowLeft => KeyCode::ArrowLeft,
+        WinitKeyCode::ArrowRight => KeyCode::ArrowRight,
+        WinitKeyCode::NumLock => KeyCode::NumLock,
+        WinitKeyCode::NumpadDivide => KeyCode::NumpadDivide,
+        WinitKeyCode::NumpadMultiply => KeyCode::NumpadMultiply,
+        WinitKeyCode::NumpadSubtract => KeyCode::NumpadSubtract,
+        WinitKeyCode::NumpadAdd => KeyCode::NumpadAdd,
+        WinitKeyCode::NumpadEnter => KeyCode::NumpadEnter,
+        WinitKeyCode::NumpadDecimal => KeyCode::NumpadDecimal,
+        WinitKeyCode::Numpad0 => KeyCode::Numpad0,
+        WinitKeyCode::Numpad1 => KeyCode::Numpad1,
+        WinitKeyCode::Numpad2 => KeyCode::Numpad2,
+        WinitKeyCode::Numpad3 => KeyCode::Numpad3,
+        WinitKeyCode::Numpad4 => KeyCode::Numpad4,
+        WinitKeyCode::Numpad5 => KeyCode::Numpad5,
+        WinitKeyCode::Numpad6 => KeyCode::Numpad6,
+        WinitKeyCode::Numpad7 => KeyCode::Numpad7,
+        WinitKeyCode::Numpad8 => KeyCode::Numpad8,
+        WinitKeyCode::Numpad9 => KeyCode::Numpad9,
+        _ => return None,
+    })
+}
+
+pub fn mouse_button(button: WinitMouseButton) -> MouseButton {
+    match button {
+        WinitMouseButton::Left => MouseButton::Left,
+        WinitMouseButton::Middle => MouseButton::Middle,
+        WinitMouseButton::Right => MouseButton::Right,
+        WinitMouseButton::Back => MouseButton::Back,
+        WinitMouseButton::Forward => MouseButton::Forward,
+        WinitMouseButton::Other(b) => MouseButton::Other(b),
+    }
+}
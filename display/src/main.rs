@@ -1,16 +1,21 @@
 use std::{
     io::Write,
-    net::TcpStream,
-    sync::{Arc, Mutex},
-    thread,
+    sync::{atomic::AtomicBool, Arc, Mutex},
     time::{Duration, Instant},
 };
 
-use client::init_client;
-use common::{chan, msgs::KeyEvent, portforward::PortForwarder};
+use audio::AudioJitterBuffer;
+use client::JitterBuffer;
+use common::{
+    clock,
+    handshake::VideoFormat,
+    msgs::{ControlMsg, KeyEvent, Modifiers, ScrollDelta},
+    secure::Identity,
+};
+use config::Config;
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Sample, SampleRate, StreamConfig,
+    OutputCallbackInfo, SampleRate, StreamConfig,
 };
 use egui_glium::{egui_winit::egui::ViewportId, EguiGlium};
 use glium::{
@@ -26,50 +31,65 @@ use glium::{
     vertex::VerticesSource,
     winit::{
         application::ApplicationHandler,
-        event::{DeviceEvent, ElementState, MouseButton},
-        keyboard::KeyCode,
+        event::{DeviceEvent, ElementState, MouseScrollDelta},
+        keyboard::{KeyCode, PhysicalKey},
         window::CursorGrabMode,
     },
     Display, Surface,
 };
+use session::{Connector, Session, UserEvent};
 use ui::Ui;
 
+mod audio;
 mod client;
+mod clocksync;
+mod config;
+mod gamepad;
+mod keymap;
 mod priveleged;
+mod session;
 mod ui;
 mod usb;
 
-const ENCODED_WIDTH: u32 = 2560;
-const ENCODED_HEIGHT: u32 = 1440;
-// const ENCODED_WIDTH: u32 = 2256;
-// const ENCODED_HEIGHT: u32 = 1504;
-// const FRAME_DURATION: Duration = Duration::from_micros(16_666);
-const FRAME_DURATION: Duration = Duration::from_micros(100_000);
+// How often the display shares its latency measurements with the capture
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(1);
 
 // If you are experiencing packet loss on linux, you may need to increase you udp buffer size
 // sudo sysctl -w net.core.rmem_max=20000000
 
 struct AppDisplay {
-    key_chan: chan::SubChanWriter,
+    // None while reconnecting to the capture
+    session: Option<Session>,
+    modifiers: Modifiers,
     window: Window,
     display: Display<WindowSurface>,
 
     texture: glium::Texture2d,
     program: glium::Program,
 
+    video: VideoFormat,
+    jitter: JitterBuffer,
+    audio: Arc<Mutex<AudioJitterBuffer>>,
     tredraw: Instant,
     ui: Ui,
+
+    last_latency_report: Instant,
 }
 
 impl AppDisplay {
     fn new(
         window: Window,
         display: Display<WindowSurface>,
-        key_chan: chan::SubChanWriter,
+        session: Session,
         egui_glium: EguiGlium,
         volume: Arc<Mutex<f32>>,
+        jitter: JitterBuffer,
+        audio: Arc<Mutex<AudioJitterBuffer>>,
+        absolute_mouse: bool,
+        clipboard: Arc<AtomicBool>,
     ) -> Self {
-        let texture = glium::Texture2d::empty(&display, ENCODED_WIDTH, ENCODED_HEIGHT).unwrap();
+        let video = session.video;
+        let texture = glium::Texture2d::empty(&display, video.width, video.height).unwrap();
 
         let program = glium::Program::from_source(
             &display,
@@ -82,46 +102,143 @@ impl AppDisplay {
         AppDisplay {
             window,
             display,
-            key_chan,
+            modifiers: Modifiers::default(),
 
             texture,
             program,
 
+            video,
+            jitter,
+            audio,
             tredraw: Instant::now(),
             ui: Ui {
                 egui_glium,
                 open: true,
                 volume,
+                jitter_stats: Default::default(),
+                audio_stats: Default::default(),
+                latency: None,
+                absolute_mouse,
+                clipboard,
+                transfers: session.files.as_ref().map(|f| f.transfers.clone()),
+                status: None,
                 quit: false,
             },
+
+            session: Some(session),
+            last_latency_report: Instant::now(),
+        }
+    }
+}
+
+impl AppDisplay {
+    // Where the video is drawn in the window, as (left, top, width, height), keeping
+    // its aspect ratio with bars on the sides or top and bottom
+    fn viewport(&self) -> (u32, u32, u32, u32) {
+        let size = self.window.inner_size();
+        let scale = f64::min(
+            size.width as f64 / self.video.width as f64,
+            size.height as f64 / self.video.height as f64,
+        );
+
+        let width = (self.video.width as f64 * scale) as u32;
+        let height = (self.video.height as f64 * scale) as u32;
+        (
+            (size.width - width) / 2,
+            (size.height - height) / 2,
+            width,
+            height,
+        )
+    }
+
+    // Input is dropped while reconnecting, or if the connection just broke
+    fn send_key(&mut self, event: &KeyEvent) {
+        if let Some(session) = &mut self.session {
+            let _ = session
+                .key_chan
+                .write_all(&rmp_serde::to_vec(event).unwrap());
         }
     }
 }
 
-impl ApplicationHandler<Vec<u8>> for AppDisplay {
+impl ApplicationHandler<UserEvent> for AppDisplay {
     fn resumed(&mut self, _event_loop: &glium::winit::event_loop::ActiveEventLoop) {}
 
     fn user_event(
         &mut self,
         _event_loop: &glium::winit::event_loop::ActiveEventLoop,
-        event: Vec<u8>,
+        event: UserEvent,
     ) {
-        // Write image to texture
-        let t = Instant::now();
-        self.texture = glium::Texture2d::with_mipmaps(
-            &self.display,
-            RawImage2d::from_raw_rgba(event, (ENCODED_WIDTH, ENCODED_HEIGHT)),
-            glium::texture::MipmapsOption::NoMipmap,
-        )
-        .unwrap();
-        println!(
-            "!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!  written image to texture after {} us",
-            Instant::now().duration_since(t).as_micros()
-        );
+        match event {
+            // Hold the frame until it's due
+            UserEvent::Frame(frame) => self.jitter.push(frame),
+            UserEvent::Connected(session) => {
+                // The capture may have been restarted with a different size
+                if session.video != self.video {
+                    self.video = session.video;
+                    self.texture =
+                        glium::Texture2d::empty(&self.display, self.video.width, self.video.height)
+                            .unwrap();
+                }
+                self.jitter.reset();
+
+                self.ui.transfers = session.files.as_ref().map(|f| f.transfers.clone());
+                self.ui.status = None;
+                self.session = Some(session);
+            }
+            UserEvent::Disconnected(reason) => {
+                self.session = None;
+                self.ui.status = Some(format!("Reconnecting, {}", reason));
+            }
+        }
     }
 
     fn about_to_wait(&mut self, _event_loop: &glium::winit::event_loop::ActiveEventLoop) {
-        if Instant::now().duration_since(self.tredraw) > FRAME_DURATION {
+        // Keep the audio and video delays in line with each other
+        {
+            let mut audio = self.audio.lock().unwrap();
+            self.jitter.set_audio_delay(audio.stats().delay_us);
+            if let Some(delay) = self.jitter.base_delay_us() {
+                audio.set_min_delay(delay);
+            }
+        }
+
+        if let Some(frame) = self.jitter.pop_due() {
+            // Measure the latency of this frame, sharing it with the capture now and then
+            if let Some(session) = &mut self.session {
+                let report = session
+                    .clock
+                    .lock()
+                    .unwrap()
+                    .report(&frame, clock::now_us());
+                if let Some(report) = report {
+                    self.ui.latency = Some(report);
+                    if Instant::now().duration_since(self.last_latency_report)
+                        > LATENCY_REPORT_INTERVAL
+                    {
+                        let _ = session
+                            .control_chan
+                            .write_all(&rmp_serde::to_vec(&ControlMsg::Latency(report)).unwrap());
+                        self.last_latency_report = Instant::now();
+                    }
+                }
+            }
+
+            // Write image to texture
+            let t = Instant::now();
+            self.texture = glium::Texture2d::with_mipmaps(
+                &self.display,
+                RawImage2d::from_raw_rgba(frame.image, (self.video.width, self.video.height)),
+                glium::texture::MipmapsOption::NoMipmap,
+            )
+            .unwrap();
+            println!(
+                "!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!  written image to texture after {} us",
+                Instant::now().duration_since(t).as_micros()
+            );
+
+            self.window.request_redraw();
+        } else if Instant::now().duration_since(self.tredraw) > self.video.frame_duration() {
             self.window.request_redraw();
         }
     }
@@ -138,6 +255,18 @@ impl ApplicationHandler<Vec<u8>> for AppDisplay {
                     event_loop.exit();
                 }
                 WindowEvent::Resized(_) => {}
+                WindowEvent::DroppedFile(path) => match &self.session {
+                    Some(Session {
+                        files: Some(files), ..
+                    }) => {
+                        println!("sending {:?}", path);
+                        files.send(path);
+                    }
+                    Some(_) => println!("the capture can't receive files"),
+                    None => {
+                        println!("not connected to the capture, drop it again once reconnected")
+                    }
+                },
                 WindowEvent::KeyboardInput {
                     device_id: _,
                     event: ref kevent,
@@ -145,16 +274,17 @@ impl ApplicationHandler<Vec<u8>> for AppDisplay {
                 } => {
                     println!("got keyboard event {:?}", kevent.physical_key);
                     match kevent.physical_key {
-                        glium::winit::keyboard::PhysicalKey::Code(KeyCode::F7) => {
+                        PhysicalKey::Code(KeyCode::F7) => {
                             if kevent.state != ElementState::Released {
                                 return;
                             }
 
                             self.ui.open = !self.ui.open;
 
-                            // Lock and hide the cursor, or inverse
+                            // Lock and hide the cursor, or inverse. In absolute mode it's
+                            // left free, with the capture's cursor drawn in the video instead
                             self.window.set_cursor_visible(self.ui.open);
-                            if self.ui.open {
+                            if self.ui.open || self.ui.absolute_mouse {
                                 self.window.set_cursor_grab(CursorGrabMode::None).unwrap();
                             } else {
                                 self.window
@@ -175,24 +305,44 @@ impl ApplicationHandler<Vec<u8>> for AppDisplay {
                         return;
                     }
 
-                    // TODO Accept all key events
-                    let key_text = kevent.logical_key.to_text();
-                    match key_text {
-                        Some(t) => {
-                            self.key_chan
-                                .write_all(
-                                    &rmp_serde::to_vec(&KeyEvent::Key {
-                                        letter: t.chars().nth(0).unwrap(),
-                                        state: match kevent.state {
-                                            ElementState::Pressed => true,
-                                            ElementState::Released => false,
-                                        },
-                                    })
-                                    .unwrap(),
-                                )
-                                .unwrap();
+                    let state = kevent.state == ElementState::Pressed;
+                    let key_event = match kevent.physical_key {
+                        PhysicalKey::Code(code) => {
+                            keymap::key_code(code).map(|code| KeyEvent::Physical {
+                                code,
+                                modifiers: self.modifiers,
+                                state,
+                            })
+                        }
+                        PhysicalKey::Unidentified(_) => None,
+                    };
+
+                    match key_event {
+                        // The capture repeats held keys itself
+                        Some(_) if kevent.repeat => {}
+                        Some(event) => self.send_key(&event),
+                        // Fall back to typing the text of keys the capture can't press
+                        None => {
+                            if let Some(t) = kevent.logical_key.to_text() {
+                                self.send_key(&KeyEvent::Key {
+                                    letter: t.chars().nth(0).unwrap(),
+                                    state,
+                                });
+                            }
                         }
-                        None => {}
+                    }
+                }
+                WindowEvent::ModifiersChanged(modifiers) => {
+                    let state = modifiers.state();
+                    self.modifiers = Modifiers {
+                        shift: state.shift_key(),
+                        ctrl: state.control_key(),
+                        alt: state.alt_key(),
+                        meta: state.super_key(),
+                    };
+
+                    if self.ui.open {
+                        let _ = self.ui.egui_glium.on_event(&self.window, &event);
                     }
                 }
                 WindowEvent::MouseInput {
@@ -205,31 +355,52 @@ impl ApplicationHandler<Vec<u8>> for AppDisplay {
                         return;
                     }
 
-                    let but = match button {
-                        MouseButton::Left => 0,
-                        MouseButton::Middle => 1,
-                        MouseButton::Right => 2,
-                        _ => 3,
-                    };
-                    if but < 3 {
-                        self.key_chan
-                            .write_all(
-                                &rmp_serde::to_vec(&KeyEvent::Click {
-                                    button: but,
-                                    state: state.is_pressed(),
-                                })
-                                .unwrap(),
-                            )
-                            .unwrap();
+                    self.send_key(&KeyEvent::Click {
+                        button: keymap::mouse_button(button),
+                        state: state.is_pressed(),
+                    });
+                }
+                WindowEvent::MouseWheel {
+                    device_id: _,
+                    delta,
+                    phase: _,
+                } => {
+                    if self.ui.open {
+                        let _ = self.ui.egui_glium.on_event(&self.window, &event);
+                        return;
                     }
+
+                    let delta = match delta {
+                        MouseScrollDelta::LineDelta(x, y) => ScrollDelta::Lines { x, y },
+                        MouseScrollDelta::PixelDelta(p) => ScrollDelta::Pixels { x: p.x, y: p.y },
+                    };
+                    self.send_key(&KeyEvent::Scroll { delta });
                 }
                 WindowEvent::CursorMoved {
                     device_id: _,
-                    position: _,
+                    position,
                 } => {
                     if self.ui.open {
                         let _ = self.ui.egui_glium.on_event(&self.window, &event);
+                        return;
                     }
+
+                    if !self.ui.absolute_mouse {
+                        return;
+                    }
+
+                    // Ignore the cursor while it's over the letterboxing
+                    let (left, top, width, height) = self.viewport();
+                    let x = (position.x - left as f64) / width as f64;
+                    let y = (position.y - top as f64) / height as f64;
+                    if !(0. ..1.).contains(&x) || !(0. ..1.).contains(&y) {
+                        return;
+                    }
+
+                    self.send_key(&KeyEvent::MouseAbs {
+                        x: (x * self.video.width as f64) as u32,
+                        y: (y * self.video.height as f64) as u32,
+                    });
                 }
                 WindowEvent::RedrawRequested => {
                     if self.ui.quit {
@@ -245,7 +416,11 @@ impl ApplicationHandler<Vec<u8>> for AppDisplay {
 
                     println!("*****************************  redrawing");
 
+                    let (left, top, width, height) = self.viewport();
+                    let window_height = self.window.inner_size().height;
+
                     let mut target = self.display.draw();
+                    target.clear_color(0., 0., 0., 1.);
                     target
                         .draw(
                             VerticesSource::Marker {
@@ -255,10 +430,21 @@ impl ApplicationHandler<Vec<u8>> for AppDisplay {
                             NoIndices(glium::index::PrimitiveType::TrianglesList),
                             &self.program,
                             &uniform! {frag_tex: self.texture.sampled()},
-                            &glium::DrawParameters::default(),
+                            &glium::DrawParameters {
+                                // Measured from the bottom of the window
+                                viewport: Some(glium::Rect {
+                                    left,
+                                    bottom: window_height - top - height,
+                                    width,
+                                    height,
+                                }),
+                                ..Default::default()
+                            },
                         )
                         .unwrap();
 
+                    self.ui.jitter_stats = self.jitter.stats();
+                    self.ui.audio_stats = self.audio.lock().unwrap().stats();
                     self.ui.redraw(&self.window, &self.display, &mut target);
 
                     target.finish().unwrap();
@@ -276,17 +462,12 @@ impl ApplicationHandler<Vec<u8>> for AppDisplay {
     ) {
         match event {
             DeviceEvent::MouseMotion { delta } => {
-                if !self.ui.open {
+                if !self.ui.open && !self.ui.absolute_mouse {
                     // Send the delta position
-                    self.key_chan
-                        .write_all(
-                            &rmp_serde::to_vec(&KeyEvent::Mouse {
-                                x: delta.0,
-                                y: delta.1,
-                            })
-                            .unwrap(),
-                        )
-                        .unwrap();
+                    self.send_key(&KeyEvent::Mouse {
+                        x: delta.0,
+                        y: delta.1,
+                    });
                 }
             }
             _ => {}
@@ -300,16 +481,28 @@ fn main() {
         priveleged::priveleged_entrypoint();
     }
 
-    let mut ipc_writer = priveleged::start_priveleged_process();
+    let config = Config::load();
 
-    // TODO Ask user which device to share first
-    usb::start_usbip_server(ipc_writer.as_mut());
+    if !config.usb_devices.is_empty() {
+        let mut ipc_writer = priveleged::start_priveleged_process();
+        usb::start_usbip_server(ipc_writer.as_mut(), config.usb_devices.clone());
+    }
 
     // Create audio stream on main thread
     let host = cpal::default_host();
-    let device = host.default_output_device().unwrap();
-    let decoded_audio = Arc::new(Mutex::new(vec![]));
-    let decoded_audio_cb = decoded_audio.clone();
+    let device = match &config.audio_device {
+        Some(name) => host
+            .output_devices()
+            .unwrap()
+            .find(|d| d.name().is_ok_and(|n| &n == name))
+            .unwrap_or_else(|| {
+                eprintln!("could not find audio output device {:?}", name);
+                std::process::exit(1);
+            }),
+        None => host.default_output_device().unwrap(),
+    };
+    let audio = Arc::new(Mutex::new(AudioJitterBuffer::new()));
+    let audio_cb = audio.clone();
 
     let volume = Arc::new(Mutex::new(100.0f32));
     let volume_cb = volume.clone();
@@ -321,19 +514,18 @@ fn main() {
                 channels: 2,
                 buffer_size: cpal::BufferSize::Default,
             },
-            move |data: &mut [f32], &_| {
-                if decoded_audio_cb.lock().unwrap().len() >= data.len() {
-                    let volume_guard = volume_cb.lock().unwrap();
-                    println!("volume {}", *volume_guard / 100.);
-                    let decoded: Vec<f32> = decoded_audio_cb.lock().unwrap()[0..data.len()]
-                        .iter()
-                        .map(|x| x * (*volume_guard) / 100.)
-                        .collect();
-
-                    decoded_audio_cb.lock().unwrap().drain(0..data.len());
-                    data.copy_from_slice(&decoded);
-                } else {
-                    data.fill(Sample::EQUILIBRIUM);
+            move |data: &mut [f32], info: &OutputCallbackInfo| {
+                // How long until this buffer is heard
+                let timestamp = info.timestamp();
+                let latency = timestamp
+                    .playback
+                    .duration_since(&timestamp.callback)
+                    .unwrap_or_default();
+                audio_cb.lock().unwrap().fill(data, latency);
+
+                let volume = *volume_cb.lock().unwrap() / 100.;
+                for x in data.iter_mut() {
+                    *x *= volume;
                 }
             },
             move |err| {
@@ -346,7 +538,7 @@ fn main() {
     stream.play().unwrap();
 
     // Create window
-    let event_loop = EventLoop::<Vec<u8>>::with_user_event().build().unwrap();
+    let event_loop = EventLoop::<UserEvent>::with_user_event().build().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
     let (window, display) = glium::backend::glutin::SimpleWindowBuilder::new()
         .with_inner_size(1920, 1080)
@@ -356,30 +548,42 @@ fn main() {
 
     let egui_glium = egui_glium::EguiGlium::new(ViewportId::ROOT, &display, &window, &event_loop);
 
-    let mut c = init_client(decoded_audio, event_loop.create_proxy());
-
-    let tcp_sock = TcpStream::connect("dw.superkooks.com:42069").unwrap();
-    let master_chan = Arc::new(Mutex::new(chan::TcpChan::new(tcp_sock)));
-    let portforwarder = PortForwarder::new(master_chan.clone());
-    portforwarder.listen_and_forward("127.0.0.1:7800".parse().unwrap(), "google.com:80".into());
-
-    // Create thread to read udp and decode frames
-    thread::spawn(move || {
-        c.init();
-        c.run()
+    let identity = Identity::load_or_create(&config.identity_file).unwrap_or_else(|e| {
+        eprintln!(
+            "could not load identity from {:?}: {}",
+            config.identity_file, e
+        );
+        std::process::exit(1);
     });
+    println!(
+        "connecting as {:?} with fingerprint {}, accept it on the capture",
+        config.name,
+        identity.fingerprint()
+    );
+
+    // Connect before showing anything, then keep reconnecting in the background
+    let clipboard = Arc::new(AtomicBool::new(config.clipboard));
+    let mut connector = Connector::new(
+        config.clone(),
+        identity,
+        audio.clone(),
+        event_loop.create_proxy(),
+        clipboard.clone(),
+    );
+    let session = connector.connect();
+    connector.reconnect_on_loss();
 
     // Create instance to display frames and capture events
     let mut d = AppDisplay::new(
         window,
         display,
-        master_chan
-            .lock()
-            .unwrap()
-            .create_subchan(chan::ChannelId::Keys)
-            .0,
+        session,
         egui_glium,
         volume,
+        JitterBuffer::new(config.target_latency()),
+        audio,
+        config.absolute_mouse,
+        clipboard,
     );
 
     // Run its event loop
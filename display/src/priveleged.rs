@@ -5,13 +5,13 @@ use std::{
     process::Command,
 };
 
-use crate::usb;
+use crate::{config::UsbDevice, usb};
 use common::chan;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
 pub enum IPCMsg {
-    StartUSBIP {},
+    StartUSBIP { devices: Vec<UsbDevice> },
 }
 
 pub fn start_priveleged_process() -> Option<chan::SubChanWriter> {
@@ -44,8 +44,8 @@ pub fn priveleged_entrypoint() -> ! {
         let msg: IPCMsg = rmp_serde::from_read(r.by_ref()).unwrap();
 
         match msg {
-            IPCMsg::StartUSBIP {} => {
-                usb::internal_start_usbip();
+            IPCMsg::StartUSBIP { devices } => {
+                usb::internal_start_usbip(devices);
             }
         }
     }
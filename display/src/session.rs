@@ -0,0 +1,295 @@
+use std::{
+    io::{self, Write},
+    net::TcpStream,
+    sync::{
+        atomic::AtomicBool,
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, sleep},
+};
+
+use common::{
+    chan::{self, SubChanWriter, TcpChan},
+    clipboard,
+    filetransfer::FileSender,
+    handshake::{
+        self, AudioFormat, Codec, HandshakeError, Negotiated, SessionHello, VideoFormat,
+        FEATURE_AUDIO, FEATURE_CLIPBOARD, FEATURE_FILE_TRANSFER, FEATURE_GAMEPAD,
+        FEATURE_PORT_FORWARD, PROTOCOL_VERSION,
+    },
+    portforward::PortForwarder,
+    repeater::{Backoff, Hello, Role, TcpHello},
+    secure::{self, Identity, PairingCode, SecureError},
+};
+use glium::winit::event_loop::EventLoopProxy;
+
+use crate::{
+    audio::AudioJitterBuffer,
+    client::{connect_udp, init_client, DecodedFrame},
+    clocksync::{start_clock_sync, ClockSync},
+    config::Config,
+    gamepad,
+};
+
+// Sent to the event loop by the other threads
+pub enum UserEvent {
+    Frame(DecodedFrame),
+    Connected(Session),
+    // Why the connection to the capture was lost
+    Disconnected(String),
+}
+
+// What the window uses of a connection to the capture, replaced after reconnecting
+pub struct Session {
+    pub video: VideoFormat,
+    pub key_chan: SubChanWriter,
+    pub control_chan: SubChanWriter,
+    pub clock: Arc<Mutex<ClockSync>>,
+    pub files: Option<FileSender>,
+}
+
+// Why connecting to the capture failed
+enum ConnectError {
+    // Lost the repeater or the capture on the way, so try again
+    Retry(String),
+    // Trying again won't help, e.g. the capture rejected us
+    Fatal(String),
+}
+
+impl From<io::Error> for ConnectError {
+    fn from(e: io::Error) -> Self {
+        ConnectError::Retry(e.to_string())
+    }
+}
+
+// Connects to the capture, and again whenever the connection is lost
+pub struct Connector {
+    config: Config,
+    code: PairingCode,
+    identity: Identity,
+    audio: Arc<Mutex<AudioJitterBuffer>>,
+    el_proxy: EventLoopProxy<UserEvent>,
+    clipboard: Arc<AtomicBool>,
+
+    // Given by the repeater when last paired, proving it's us pairing again
+    token: Option<u64>,
+    // Kept across connections, so forwarded ports keep listening
+    portforwarder: Option<(Arc<Mutex<TcpChan>>, PortForwarder)>,
+    // Told why each connection was lost
+    lost_tx: Sender<String>,
+    lost_rx: Receiver<String>,
+}
+
+impl Connector {
+    pub fn new(
+        config: Config,
+        identity: Identity,
+        audio: Arc<Mutex<AudioJitterBuffer>>,
+        el_proxy: EventLoopProxy<UserEvent>,
+        clipboard: Arc<AtomicBool>,
+    ) -> Self {
+        let (lost_tx, lost_rx) = mpsc::channel();
+        Self {
+            code: config.pairing_code(),
+            config,
+            identity,
+            audio,
+            el_proxy,
+            clipboard,
+            token: None,
+            portforwarder: None,
+            lost_tx,
+            lost_rx,
+        }
+    }
+
+    // Keeps trying until connected, exiting if it never will be
+    pub fn connect(&mut self) -> Session {
+        let mut backoff = Backoff::default();
+        loop {
+            match self.try_connect() {
+                Ok(session) => return session,
+                Err(ConnectError::Retry(e)) => {
+                    let delay = backoff.next_delay();
+                    eprintln!(
+                        "could not connect to the capture: {}, trying again in {:?}",
+                        e, delay
+                    );
+                    sleep(delay);
+                }
+                Err(ConnectError::Fatal(e)) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    // Reconnects whenever the connection is lost, telling the event loop about both
+    pub fn reconnect_on_loss(mut self) {
+        thread::spawn(move || loop {
+            let reason = self.lost_rx.recv().unwrap();
+            eprintln!("lost connection to the capture: {}", reason);
+            if self
+                .el_proxy
+                .send_event(UserEvent::Disconnected(reason))
+                .is_err()
+            {
+                return;
+            }
+
+            let session = self.connect();
+            println!("reconnected to the capture");
+            if self
+                .el_proxy
+                .send_event(UserEvent::Connected(session))
+                .is_err()
+            {
+                return;
+            }
+        });
+    }
+
+    fn try_connect(&mut self) -> Result<Session, ConnectError> {
+        let hello = Hello {
+            session: self.code.session.clone(),
+            role: Role::Display,
+            resume: self.token,
+        };
+        let (udp_sock, paired) = connect_udp(&self.config.server, &hello)?;
+        // Lets the next attempt take over from this one, even if it fails from here on
+        self.token = Some(paired.token);
+
+        let mut tcp_sock = TcpStream::connect(&self.config.server)?;
+        let tcp_hello = TcpHello {
+            session: hello.session,
+            role: hello.role,
+            token: paired.token,
+        };
+        tcp_sock.write_all(&rmp_serde::to_vec(&tcp_hello).unwrap())?;
+
+        // Authenticate the capture and agree on the keys for everything sent after this
+        let secure =
+            match secure::connect(&mut tcp_sock, &self.code, &self.identity, &self.config.name) {
+                Ok(secure) => Arc::new(secure),
+                Err(e @ SecureError::Io(_)) => return Err(ConnectError::Retry(e.to_string())),
+                Err(e) => return Err(ConnectError::Fatal(e.to_string())),
+            };
+        let chan = TcpChan::new_secure(tcp_sock, secure.clone());
+
+        let negotiated = match self.negotiate(&chan) {
+            Ok(n) => n,
+            Err(e) => {
+                chan.close();
+                return Err(e);
+            }
+        };
+        println!("negotiated {:?}", negotiated);
+
+        let (control_writer, control_reader) = chan.create_subchan(chan::ChannelId::Control);
+        let clock = start_clock_sync(control_writer.clone(), control_reader);
+
+        // Create thread to read udp and decode frames
+        let mut c = init_client(
+            udp_sock,
+            secure,
+            negotiated.video,
+            self.audio.clone(),
+            self.el_proxy.clone(),
+            control_writer.clone(),
+            chan.clone(),
+        );
+        thread::spawn(move || {
+            c.init();
+            c.run()
+        });
+
+        match &self.portforwarder {
+            Some((master, p)) => {
+                *master.lock().unwrap() = chan.clone();
+                p.resume(true);
+            }
+            None => {
+                let master = Arc::new(Mutex::new(chan.clone()));
+                let p = PortForwarder::new(master.clone(), true);
+                if negotiated.features & FEATURE_PORT_FORWARD != 0 {
+                    for f in &self.config.forwards {
+                        if let Err(e) = p.listen_and_forward(f.listen, f.forward.clone()) {
+                            eprintln!(
+                                "could not listen on {} to forward to {}: {}",
+                                f.listen, f.forward, e
+                            );
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                self.portforwarder = Some((master, p));
+            }
+        }
+
+        if negotiated.features & FEATURE_GAMEPAD != 0 {
+            let (gamepad_writer, gamepad_reader) = chan.create_subchan(chan::ChannelId::Gamepad);
+            gamepad::start_gamepads(gamepad_writer, gamepad_reader);
+        }
+
+        if negotiated.features & FEATURE_CLIPBOARD != 0 {
+            let (clipboard_writer, clipboard_reader) =
+                chan.create_subchan(chan::ChannelId::Clipboard);
+            if let Err(e) = clipboard::start_clipboard_sync(
+                clipboard_writer,
+                clipboard_reader,
+                self.clipboard.clone(),
+            ) {
+                eprintln!("could not share the clipboard: {}", e);
+            }
+        }
+
+        let files = (negotiated.features & FEATURE_FILE_TRANSFER != 0).then(|| {
+            let (files_writer, files_reader) = chan.create_subchan(chan::ChannelId::FileTransfer);
+            FileSender::new(files_writer, files_reader)
+        });
+
+        let key_chan = chan.create_subchan(chan::ChannelId::Keys).0;
+
+        // Only watched once connected, so a failed attempt isn't reported as lost
+        let lost_tx = self.lost_tx.clone();
+        chan.on_close(move |state| {
+            let _ = lost_tx.send(state.to_string());
+        });
+
+        Ok(Session {
+            video: negotiated.video,
+            key_chan,
+            control_chan: control_writer,
+            clock,
+            files,
+        })
+    }
+
+    // Agrees on the session parameters with the capture
+    fn negotiate(&self, chan: &TcpChan) -> Result<Negotiated, ConnectError> {
+        let ours = SessionHello {
+            version: PROTOCOL_VERSION,
+            codecs: vec![Codec::H264],
+            video: None,
+            audio: AudioFormat {
+                sample_rate: 48000,
+                channels: 2,
+            },
+            features: FEATURE_AUDIO
+                | FEATURE_PORT_FORWARD
+                | FEATURE_GAMEPAD
+                | FEATURE_CLIPBOARD
+                | FEATURE_FILE_TRANSFER,
+        };
+        handshake::exchange(chan, &ours)
+            .and_then(|theirs| handshake::negotiate(&theirs, &ours))
+            .map_err(|e| match e {
+                HandshakeError::Io(_) | HandshakeError::Decode(_) => {
+                    ConnectError::Retry(e.to_string())
+                }
+                e => ConnectError::Fatal(e.to_string()),
+            })
+    }
+}
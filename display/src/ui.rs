@@ -1,13 +1,33 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
 
 use egui_glium::EguiGlium;
 use glium::{glutin::surface::WindowSurface, winit::window::Window, Display};
 
+use common::{
+    filetransfer::{Transfer, TransferStatus},
+    msgs::LatencyReport,
+};
+
+use crate::{audio::AudioStats, client::JitterStats};
+
 pub struct Ui {
     pub open: bool,
     pub egui_glium: EguiGlium,
 
     pub volume: Arc<Mutex<f32>>,
+    pub jitter_stats: JitterStats,
+    pub audio_stats: AudioStats,
+    pub latency: Option<LatencyReport>,
+    // Move the capture's pointer to where ours is, instead of by our mouse's motion
+    pub absolute_mouse: bool,
+    pub clipboard: Arc<AtomicBool>,
+    // Files dropped onto the window, None if the capture can't receive them
+    pub transfers: Option<Arc<Mutex<Vec<Transfer>>>>,
+    // Why the connection was lost, while reconnecting
+    pub status: Option<String>,
     pub quit: bool,
 }
 
@@ -31,6 +51,11 @@ impl Ui {
                     ui.heading("Settings");
                     ui.label("   F7 to close");
 
+                    if let Some(status) = &self.status {
+                        ui.add_space(15.);
+                        ui.label(status);
+                    }
+
                     ui.add_space(15.);
 
                     ui.label("Volume");
@@ -41,6 +66,77 @@ impl Ui {
 
                     ui.add_space(15.);
 
+                    ui.checkbox(&mut self.absolute_mouse, "Absolute mouse")
+                        .on_hover_text(
+                            "Follow the cursor for desktop use, instead of locking it for games",
+                        );
+
+                    let mut clipboard = self.clipboard.load(Ordering::Relaxed);
+                    if ui.checkbox(&mut clipboard, "Share clipboard").changed() {
+                        self.clipboard.store(clipboard, Ordering::Relaxed);
+                    }
+
+                    ui.add_space(15.);
+
+                    ui.label(format!("Frames presented: {}", self.jitter_stats.presented));
+                    ui.label(format!("Frames late: {}", self.jitter_stats.late));
+                    ui.label(format!("Frames dropped: {}", self.jitter_stats.dropped));
+                    ui.label(format!("Frames buffered: {}", self.jitter_stats.buffered));
+
+                    ui.add_space(15.);
+
+                    ui.label(format!("Audio buffer: {} packets", self.audio_stats.depth));
+                    ui.label(format!("Audio concealed: {}", self.audio_stats.concealed));
+                    ui.label(format!("Audio recovered: {}", self.audio_stats.recovered));
+                    ui.label(format!("Audio underruns: {}", self.audio_stats.underruns));
+                    match self.jitter_stats.av_offset_us {
+                        Some(offset) => ui.label(format!("A/V offset: {} ms", offset / 1000)),
+                        None => ui.label("A/V offset: -"),
+                    };
+
+                    ui.add_space(15.);
+
+                    match self.latency {
+                        Some(l) => {
+                            ui.label(format!("Glass to glass: {} ms", l.total_us / 1000));
+                            ui.label(format!("   Capture: {} ms", l.capture_us / 1000));
+                            ui.label(format!("   Encode: {} ms", l.encode_us / 1000));
+                            ui.label(format!("   Network: {} ms", l.network_us / 1000));
+                            ui.label(format!("   Decode: {} ms", l.decode_us / 1000));
+                            ui.label(format!("   Present: {} ms", l.present_us / 1000));
+                            ui.label(format!("Round trip: {} ms", l.rtt_us / 1000));
+                        }
+                        None => {
+                            ui.label("Glass to glass: -");
+                        }
+                    }
+
+                    ui.add_space(15.);
+
+                    if let Some(transfers) = &self.transfers {
+                        ui.label("Files (drop onto the window to send)");
+                        for t in transfers.lock().unwrap().iter() {
+                            match &t.status {
+                                TransferStatus::Queued => ui.label(format!("{}: queued", t.name)),
+                                TransferStatus::Checking => {
+                                    ui.label(format!("{}: checking", t.name))
+                                }
+                                TransferStatus::Sending => ui.add(
+                                    egui::ProgressBar::new(
+                                        t.received as f32 / t.size.max(1) as f32,
+                                    )
+                                    .text(t.name.as_str()),
+                                ),
+                                TransferStatus::Done => ui.label(format!("{}: sent", t.name)),
+                                TransferStatus::Failed(e) => {
+                                    ui.label(format!("{}: failed, {}", t.name, e))
+                                }
+                            };
+                        }
+
+                        ui.add_space(15.);
+                    }
+
                     if ui.button("Quit").clicked() {
                         self.quit = true;
                     }
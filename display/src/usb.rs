@@ -8,21 +8,21 @@ use std::time::Duration;
 #[cfg(target_os = "windows")]
 use wdi::{CreateListOptions, InstallDriverOptions, PrepareDriverOptions};
 
-use crate::priveleged::IPCMsg;
+use crate::{config::UsbDevice, priveleged::IPCMsg};
 
-pub fn start_usbip_server(ipc_chan: Option<&mut SubChanWriter>) {
+pub fn start_usbip_server(ipc_chan: Option<&mut SubChanWriter>, devices: Vec<UsbDevice>) {
     match ipc_chan {
         Some(ipc_chan) => {
-            let b = rmp_serde::to_vec(&IPCMsg::StartUSBIP {}).unwrap();
+            let b = rmp_serde::to_vec(&IPCMsg::StartUSBIP { devices }).unwrap();
             ipc_chan.write_all(&b).unwrap();
         }
         None => {
-            internal_start_usbip();
+            internal_start_usbip(devices);
         }
     }
 }
 
-pub fn internal_start_usbip() {
+pub fn internal_start_usbip(devices: Vec<UsbDevice>) {
     println!("starting usbip from pid {}", process::id());
 
     simple_logging::log_to_file("z.log", log::LevelFilter::Trace).unwrap();
@@ -37,29 +37,36 @@ pub fn internal_start_usbip() {
         })
         .unwrap();
 
-        let wdi_info = list.iter_mut().filter(|d| d.pid == 0x6010).next().unwrap();
+        for wdi_info in list
+            .iter_mut()
+            .filter(|d| devices.iter().any(|u| u.matches(d.vid, d.pid)))
+        {
+            wdi::prepare_driver(
+                wdi_info,
+                std::env::temp_dir().to_str().unwrap(),
+                "wdi.inf",
+                &mut PrepareDriverOptions::default(),
+            )
+            .unwrap();
 
-        wdi::prepare_driver(
-            wdi_info,
-            std::env::temp_dir().to_str().unwrap(),
-            "wdi.inf",
-            &mut PrepareDriverOptions::default(),
-        )
-        .unwrap();
-
-        wdi::install_driver(
-            wdi_info,
-            std::env::temp_dir().to_str().unwrap(),
-            "wdi.inf",
-            &mut InstallDriverOptions::default(),
-        )
-        .unwrap();
+            wdi::install_driver(
+                wdi_info,
+                std::env::temp_dir().to_str().unwrap(),
+                "wdi.inf",
+                &mut InstallDriverOptions::default(),
+            )
+            .unwrap();
+        }
 
         thread::sleep(Duration::from_millis(3000));
     }
 
-    let usbip_server = UsbIpServer::new_from_host_with_filter(|d| {
-        if d.device_descriptor().unwrap().product_id() == 0x6010 {
+    let usbip_server = UsbIpServer::new_from_host_with_filter(move |d| {
+        let desc = d.device_descriptor().unwrap();
+        if devices
+            .iter()
+            .any(|u| u.matches(desc.vendor_id(), desc.product_id()))
+        {
             println!("will offer usb {:?}", d.bus_number());
             return true;
         }
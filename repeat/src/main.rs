@@ -1,72 +1,156 @@
-use std::io::copy;
-use std::net::TcpListener;
-use std::net::UdpSocket;
+use std::io::{copy, ErrorKind};
+use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use common::msgs::RTMsg;
+use common::repeater::{Hello, Paired, TcpHello};
+use session::Sessions;
+
+mod session;
+
+// How often idle sessions are looked for
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
 
 fn main() {
     let sock = UdpSocket::bind("0.0.0.0:42069").unwrap();
+    // Wake up now and then to expire idle sessions even when nothing is received
+    sock.set_read_timeout(Some(EXPIRE_INTERVAL)).unwrap();
     let tcp_sock = TcpListener::bind("0.0.0.0:42069").unwrap();
 
-    let mut display = None;
-    let mut capture = None;
-
-    while display.is_none() || capture.is_none() {
-        println!("listening");
-        let mut buf = vec![0; 2048];
-        let (_, from) = sock.recv_from(&mut buf).unwrap();
-        println!("got packet from {:?}", from);
-
-        match buf[0] {
-            0 => capture = Some(from),
-            1 => display = Some(from),
-            _ => panic!("unknown packet"),
-        }
-    }
-
-    // Send confirmation
-    sock.send_to(&vec![1], capture.unwrap()).unwrap();
-    sock.send_to(&vec![1], display.unwrap()).unwrap();
+    let sessions = Arc::new(Mutex::new(Sessions::default()));
 
-    // Accept and copy tcp connections
+    // Accept tcp connections and pair them within their session
+    let tcp_sessions = sessions.clone();
     thread::spawn(move || {
         println!("will accept");
-        let mut client1 = tcp_sock.accept().unwrap();
-        let mut client2 = tcp_sock.accept().unwrap();
-        println!("accepted");
-
-        client1.0.set_nodelay(true).unwrap();
-        client2.0.set_nodelay(true).unwrap();
+        for ts in tcp_sock.incoming() {
+            let ts = match ts {
+                Ok(ts) => ts,
+                Err(e) => {
+                    println!("failed to accept tcp connection: {}", e);
+                    continue;
+                }
+            };
 
-        let mut client12 = client1.0.try_clone().unwrap();
-        let mut client22 = client2.0.try_clone().unwrap();
-
-        thread::spawn(move || copy(&mut client12, &mut client22).unwrap());
-        copy(&mut client2.0, &mut client1.0).unwrap();
+            let sessions = tcp_sessions.clone();
+            thread::spawn(move || handle_tcp(ts, sessions));
+        }
     });
 
     // Transfer between udp connections
-    let mut next_seq = 0;
+    println!("listening");
+    let mut last_expired = Instant::now();
     loop {
+        if last_expired.elapsed() >= EXPIRE_INTERVAL {
+            sessions.lock().unwrap().expire();
+            last_expired = Instant::now();
+        }
+
         let mut buf = vec![0; 2048];
-        let (size, from) = sock.recv_from(&mut buf).unwrap();
+        // The socket is shared by every session, so errors only skip the packet.
+        // On windows an unreachable peer even shows up as an error receiving
+        let (size, from) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                println!("failed to receive udp packet: {}", e);
+                continue;
+            }
+        };
+
+        let mut guard = sessions.lock().unwrap();
+        // Answered instead of passed on to the other peer, or a lost Paired would
+        // leave the peer saying hello forever
+        let hello = rmp_serde::from_slice::<Hello>(&buf[..size]).ok();
+        if let Some(token) = hello.as_ref().and_then(|h| guard.paired_token(h, from)) {
+            let b = rmp_serde::to_vec(&Paired { token }).unwrap();
+            if let Err(e) = sock.send_to(&b, from) {
+                println!("failed to send pairing to {:?}: {}", from, e);
+            }
+            continue;
+        }
+
+        match guard.route_udp(from) {
+            // Everything between the clients is encrypted, so it's passed on as is
+            Some(to) => {
+                if let Err(e) = sock.send_to(&buf[..size], to) {
+                    println!("failed to forward udp packet to {:?}: {}", to, e);
+                }
+            }
+            None => {
+                // Not part of a session yet, so it must be a hello
+                let Some(hello) = hello else {
+                    println!("received packet from unknown host {:?}", from);
+                    continue;
+                };
+                println!(
+                    "got hello from {:?} for session {} as {:?}",
+                    from, hello.session, hello.role
+                );
+
+                // Send confirmation once both ends are present, with the token
+                // each of them must present on its tcp connection
+                if let Some(peers) = guard.register_udp(&hello, from) {
+                    for (addr, token) in peers {
+                        let b = rmp_serde::to_vec(&Paired { token }).unwrap();
+                        if let Err(e) = sock.send_to(&b, addr) {
+                            println!("failed to send pairing to {:?}: {}", addr, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn handle_tcp(mut ts: TcpStream, sessions: Arc<Mutex<Sessions>>) {
+    let peer = ts.peer_addr().ok();
 
-        let msg: RTMsg = rmp_serde::from_slice(&buf[..size]).unwrap();
-        if msg.seq != next_seq {
+    // Don't let a silent connection hold a thread forever
+    ts.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let hello: TcpHello = match rmp_serde::from_read(&mut ts) {
+        Ok(hello) => hello,
+        Err(e) => {
+            println!("bad tcp handshake from {:?}: {}", peer, e);
+            return;
+        }
+    };
+    ts.set_read_timeout(None).unwrap();
+    ts.set_nodelay(true).unwrap();
+
+    let registered = sessions.lock().unwrap().register_tcp(&hello, ts);
+    let (id, mut capture, mut display) = match registered {
+        Ok(Some(pair)) => pair,
+        Ok(None) => {
             println!(
-                "missing packet from capture {} instead of {}",
-                msg.seq, next_seq
+                "accepted tcp from {:?} for session {} as {:?}",
+                peer, hello.session, hello.role
             );
+            return;
         }
-        next_seq = msg.seq + 1;
-
-        if from == display.unwrap() {
-            sock.send_to(&buf[..size], capture.unwrap()).unwrap();
-        } else if from == capture.unwrap() {
-            sock.send_to(&buf[..size], display.unwrap()).unwrap();
-        } else {
-            println!("received packet from unknown host")
+        Err(e) => {
+            // Dropping the stream closes the connection
+            println!("rejected tcp from {:?}: {}", peer, e);
+            return;
         }
-    }
+    };
+    println!("splicing session {}", hello.session);
+
+    // Copy between them until either side leaves, then tear down the session
+    let mut capture2 = capture.try_clone().unwrap();
+    let mut display2 = display.try_clone().unwrap();
+    let code = hello.session.clone();
+    let sessions2 = sessions.clone();
+    thread::spawn(move || {
+        let _ = copy(&mut capture2, &mut display2);
+        let _ = capture2.shutdown(Shutdown::Both);
+        let _ = display2.shutdown(Shutdown::Both);
+        sessions2.lock().unwrap().remove(&code, id);
+    });
+
+    let _ = copy(&mut display, &mut capture);
+    let _ = capture.shutdown(Shutdown::Both);
+    let _ = display.shutdown(Shutdown::Both);
+    sessions.lock().unwrap().remove(&hello.session, id);
 }
@@ -0,0 +1,295 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Shutdown, SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use common::repeater::{Hello, Role, TcpHello};
+
+// How long a session which hasn't been spliced yet is kept without hearing from it
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// Peers resend their hello every second until paired, so one quiet for longer than
+// this has given up and its role may be taken by another address
+const PEER_STALE: Duration = Duration::from_secs(3);
+
+pub struct Session {
+    // Distinguishes this session from a later one reusing the same code
+    id: u64,
+    udp: HashMap<Role, SocketAddr>,
+    // When each udp peer last said hello
+    heard: HashMap<Role, Instant>,
+    // Handed to each udp peer, and presented again by its tcp connection
+    tokens: HashMap<Role, u64>,
+    tcp: HashMap<Role, TcpStream>,
+    // Roles which already have a tcp connection, pending or spliced
+    bound: HashSet<Role>,
+    // Every tcp connection bound to the session, to end them if it's paired again
+    streams: Vec<TcpStream>,
+    // Once spliced, the session is removed when the splice ends instead of expiring
+    spliced: bool,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<String, Session>,
+    addrs: HashMap<SocketAddr, (String, Role)>,
+    next_id: u64,
+}
+
+impl Sessions {
+    fn get_or_create(&mut self, code: &str) -> &mut Session {
+        if !self.sessions.contains_key(code) {
+            self.next_id += 1;
+            self.sessions.insert(
+                code.to_string(),
+                Session {
+                    id: self.next_id,
+                    udp: HashMap::new(),
+                    heard: HashMap::new(),
+                    tokens: HashMap::new(),
+                    tcp: HashMap::new(),
+                    bound: HashSet::new(),
+                    streams: vec![],
+                    spliced: false,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+
+        self.sessions.get_mut(code).unwrap()
+    }
+
+    // Returns the addresses and tokens of both peers once the session has both of them
+    pub fn register_udp(
+        &mut self,
+        hello: &Hello,
+        from: SocketAddr,
+    ) -> Option<[(SocketAddr, u64); 2]> {
+        // A peer saying hello again after it's connected lost its connection, so
+        // start the session again and make both of them pair again. It has to prove
+        // it's the same peer, or anyone with the code could end the session
+        if let Some(session) = self.sessions.get(&hello.session) {
+            if session.bound.contains(&hello.role) {
+                if hello.resume.is_none()
+                    || session.tokens.get(&hello.role) != hello.resume.as_ref()
+                {
+                    println!(
+                        "ignoring hello from {:?} for {:?} of session {}, already connected",
+                        from, hello.role, hello.session
+                    );
+                    return None;
+                }
+                self.restart(&hello.session, hello.role);
+            }
+        }
+
+        let session = self.get_or_create(&hello.session);
+        // Don't let anyone else with the code take over a role which is still pairing
+        if let (Some(addr), Some(heard)) =
+            (session.udp.get(&hello.role), session.heard.get(&hello.role))
+        {
+            if *addr != from && heard.elapsed() < PEER_STALE {
+                println!(
+                    "ignoring hello from {:?} for {:?} of session {}, already taken by {:?}",
+                    from, hello.role, hello.session, addr
+                );
+                return None;
+            }
+        }
+        session.last_seen = Instant::now();
+        session.heard.insert(hello.role, Instant::now());
+        // Hellos are resent until paired, so keep the token the peer may already have
+        if session.udp.get(&hello.role) != Some(&from) || !session.tokens.contains_key(&hello.role)
+        {
+            session.tokens.insert(hello.role, rand::random());
+        }
+        if let Some(old) = session
+            .udp
+            .insert(hello.role, from)
+            .filter(|old| *old != from)
+        {
+            println!(
+                "{:?} of session {} moved from {:?} to {:?}",
+                hello.role, hello.session, old, from
+            );
+            self.addrs.remove(&old);
+        }
+        self.addrs.insert(from, (hello.session.clone(), hello.role));
+
+        let session = &self.sessions[&hello.session];
+        match (
+            session.udp.get(&Role::Capture),
+            session.udp.get(&Role::Display),
+        ) {
+            (Some(c), Some(d)) => Some([
+                (*c, session.tokens[&Role::Capture]),
+                (*d, session.tokens[&Role::Display]),
+            ]),
+            _ => None,
+        }
+    }
+
+    // Returns the token to send again to a peer which is still pairing and says hello
+    // from the address it registered with, as its Paired must have been lost
+    pub fn paired_token(&self, hello: &Hello, from: SocketAddr) -> Option<u64> {
+        let (code, role) = self.addrs.get(&from)?;
+        if *code != hello.session || *role != hello.role {
+            return None;
+        }
+        let session = self.sessions.get(code)?;
+        if session.bound.contains(role) || !session.udp.contains_key(&role.peer()) {
+            return None;
+        }
+
+        session.tokens.get(role).copied()
+    }
+
+    // Returns the address a udp packet from a session's peer should be sent to
+    pub fn route_udp(&mut self, from: SocketAddr) -> Option<SocketAddr> {
+        let (code, role) = self.addrs.get(&from)?;
+        let session = self.sessions.get_mut(code)?;
+        session.last_seen = Instant::now();
+
+        session.udp.get(&role.peer()).copied()
+    }
+
+    // Binds a tcp connection to the udp peer holding its token.
+    // Returns the session id and both tcp streams (capture, display) once both have connected
+    pub fn register_tcp(
+        &mut self,
+        hello: &TcpHello,
+        ts: TcpStream,
+    ) -> Result<Option<(u64, TcpStream, TcpStream)>, String> {
+        let session = match self.sessions.get_mut(&hello.session) {
+            Some(session) => session,
+            None => return Err(format!("unknown session {}", hello.session)),
+        };
+
+        if session.tokens.get(&hello.role) != Some(&hello.token) {
+            return Err(format!(
+                "wrong token for {:?} of session {}",
+                hello.role, hello.session
+            ));
+        }
+
+        if !session.bound.insert(hello.role) {
+            return Err(format!(
+                "duplicate connection for {:?} of session {}",
+                hello.role, hello.session
+            ));
+        }
+
+        if let Ok(clone) = ts.try_clone() {
+            session.streams.push(clone);
+        }
+        session.tcp.insert(hello.role, ts);
+        if session.tcp.len() < 2 {
+            return Ok(None);
+        }
+
+        let capture = session.tcp.remove(&Role::Capture).unwrap();
+        let display = session.tcp.remove(&Role::Display).unwrap();
+        session.spliced = true;
+        Ok(Some((session.id, capture, display)))
+    }
+
+    // Ends the session's tcp connections and forgets the other peer, under a new id so
+    // the old splice doesn't remove it when it ends
+    fn restart(&mut self, code: &str, role: Role) {
+        self.next_id += 1;
+        let session = self.sessions.get_mut(code).unwrap();
+        session.id = self.next_id;
+        for ts in session.streams.drain(..) {
+            let _ = ts.shutdown(Shutdown::Both);
+        }
+        session.tcp.clear();
+        session.bound.clear();
+        session.tokens.clear();
+        session.spliced = false;
+        if let Some(old) = session.udp.remove(&role.peer()) {
+            self.addrs.remove(&old);
+        }
+        session.heard.clear();
+        println!("{:?} of session {} reconnecting", role, code);
+    }
+
+    pub fn remove(&mut self, code: &str, id: u64) {
+        match self.sessions.get(code) {
+            Some(session) if session.id == id => {}
+            _ => return,
+        }
+
+        let session = self.sessions.remove(code).unwrap();
+        for addr in session.udp.values() {
+            self.addrs.remove(addr);
+        }
+        println!("session {} closed", code);
+    }
+
+    // Removes sessions which were never spliced and haven't been heard from in a while,
+    // e.g. a peer which gave up before the other one arrived
+    pub fn expire(&mut self) {
+        let idle: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| !s.spliced && s.last_seen.elapsed() > IDLE_TIMEOUT)
+            .map(|(code, s)| (code.clone(), s.id))
+            .collect();
+
+        for (code, id) in idle {
+            for ts in &self.sessions[&code].streams {
+                let _ = ts.shutdown(Shutdown::Both);
+            }
+            println!("session {} expired", code);
+            self.remove(&code, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(role: Role) -> Hello {
+        Hello {
+            session: "123456".into(),
+            role,
+            resume: None,
+        }
+    }
+
+    #[test]
+    fn hello_again_gets_the_same_token() {
+        let capture: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let display: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        let mut sessions = Sessions::default();
+
+        assert!(sessions
+            .register_udp(&hello(Role::Capture), capture)
+            .is_none());
+        // Nothing to send again until the other peer is there
+        assert!(sessions
+            .paired_token(&hello(Role::Capture), capture)
+            .is_none());
+        let [(_, capture_token), (_, display_token)] = sessions
+            .register_udp(&hello(Role::Display), display)
+            .unwrap();
+
+        assert_eq!(
+            sessions.paired_token(&hello(Role::Capture), capture),
+            Some(capture_token)
+        );
+        assert_eq!(
+            sessions.paired_token(&hello(Role::Display), display),
+            Some(display_token)
+        );
+        // Only for the role and session the address registered with
+        assert!(sessions
+            .paired_token(&hello(Role::Display), capture)
+            .is_none());
+        assert!(sessions
+            .paired_token(&hello(Role::Capture), "10.0.0.3:3000".parse().unwrap())
+            .is_none());
+    }
+}